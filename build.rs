@@ -0,0 +1,61 @@
+// Generates the kernel's symbol table (see src/symbols.rs) from target/ksyms.txt,
+// which build_bootimage.sh writes with `nm` after every build. The first build
+// gets an empty table, the script keeps rebuilding until the table describes the
+// binary it is part of.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// `nm -C` leaves the hash of legacy mangled Rust names on, e.g. `foo::bar::h0123456789abcdef`
+fn strip_hash(name: &str) -> &str {
+    match name.rfind("::h") {
+        Some(i) if name.len() - i == 19 && name[i + 3..].bytes().all(|b| b.is_ascii_hexdigit()) => &name[..i],
+        _ => name,
+    }
+}
+
+/// one `nm -n -S` line: address, optional size, type, name
+fn parse_line(line: &str) -> Option<(u64, u64, &str)> {
+    let mut fields = line.splitn(4, ' ');
+    let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let second = fields.next()?;
+    let (size, kind, name) = if second.len() == 1 {
+        // symbols without a size only have three fields, the name may contain spaces
+        let rest = line.splitn(3, ' ').nth(2)?;
+        (0, second, rest)
+    } else {
+        (u64::from_str_radix(second, 16).ok()?, fields.next()?, fields.next()?)
+    };
+    if !matches!(kind, "t" | "T" | "w" | "W") {
+        return None;
+    }
+    Some((addr, size, strip_hash(name.trim())))
+}
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let ksyms = Path::new(&manifest_dir).join("target").join("ksyms.txt");
+    println!("cargo:rerun-if-changed={}", ksyms.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut symbols: Vec<(u64, u64, String)> = fs::read_to_string(&ksyms)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(parse_line)
+        .map(|(addr, size, name)| (addr, size, name.to_string()))
+        .collect();
+    symbols.sort_by_key(|s| s.0);
+    symbols.dedup_by_key(|s| s.0);
+
+    let mut out = String::new();
+    writeln!(out, "static SYMBOLS: [(u64, u64, &str); {}] = [", symbols.len()).unwrap();
+    for (addr, size, name) in &symbols {
+        writeln!(out, "    ({:#x}, {:#x}, {:?}),", addr, size, name).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("ksyms.rs");
+    fs::write(dest, out).unwrap();
+}
@@ -1,3 +1,20 @@
 # do with --release to build release
-cargo bootimage $@
+#
+# the kernel embeds its own symbol table (see build.rs), generated from the
+# previous build's nm output. rebuild until that output stops changing, so the
+# table matches the binary it ended up in.
+profile=debug
+case " $* " in
+    *" --release "*) profile=release ;;
+esac
+kernel=target/x86_64-blog_os/$profile/hash_os
 
+for pass in 1 2 3 4; do
+    cargo bootimage "$@" || exit 1
+    nm -C -n -S --defined-only "$kernel" > target/ksyms.new
+    if cmp -s target/ksyms.new target/ksyms.txt; then
+        break
+    fi
+    mv target/ksyms.new target/ksyms.txt
+done
+rm -f target/ksyms.new
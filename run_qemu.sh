@@ -1 +1 @@
-qemu-system-x86_64 -drive format=raw,file=target/x86_64-blog_os/debug/bootimage-hash_os.bin
+qemu-system-x86_64 -drive format=raw,file=target/x86_64-blog_os/debug/bootimage-hash_os.bin -device isa-debug-exit,iobase=0xf4,iosize=0x04 -smp 4 -serial stdio
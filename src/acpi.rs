@@ -0,0 +1,621 @@
+//! ACPI table discovery and parsing
+//! https://wiki.osdev.org/RSDP
+//! https://uefi.org/specs/ACPI/6.4/05_ACPI_Software_Programming_Model/ACPI_Software_Programming_Model.html
+//!
+//! the bootloader doesn't hand us the RSDP, so it is searched for in the BIOS
+//! areas and everything is read through the physical memory mapping.
+//! there is no heap, so every table is decoded into fixed size arrays.
+
+use core::fmt;
+use core::ptr::read_unaligned;
+use crate::lock::Mutex;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+use crate::{println, println_colored};
+use crate::vga_buffer::WARNING_COLOR;
+
+pub const MAX_TABLES: usize = 32;
+pub const MAX_LOCAL_APICS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_LAPIC_NMIS: usize = 8;
+pub const MAX_MCFG_ENTRIES: usize = 8;
+
+/// size of the common header every system description table starts with
+const SDT_HEADER_LEN: usize = 36;
+
+/// real mode pointer to the extended BIOS data area
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// FADT flag: the reset register is supported
+pub const FADT_RESET_REG_SUP: u32 = 1 << 10;
+/// MADT flag: there are also 8259 PICs in the system
+pub const MADT_PCAT_COMPAT: u32 = 1;
+
+static ACPI: Mutex<Option<AcpiInfo>> = Mutex::new("ACPI", None);
+
+fn read<T: Copy>(phys: u64) -> T {
+    unsafe { read_unaligned(phys_to_virt(PhysAddr::new(phys)).as_ptr::<T>()) }
+}
+
+/// sum of all bytes has to be 0 for a valid table
+fn checksum_ok(phys: u64, len: usize) -> bool {
+    let mut sum: u8 = 0;
+    for i in 0..len as u64 {
+        sum = sum.wrapping_add(read::<u8>(phys + i));
+    }
+    sum == 0
+}
+
+/// prints a fixed size byte field like an OEM id
+pub struct Ascii<'a>(pub &'a [u8]);
+
+impl fmt::Display for Ascii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &b in self.0 {
+            let c = if (0x20..0x7f).contains(&b) { b as char } else { ' ' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// an entry of the RSDT/XSDT
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub signature: [u8; 4],
+    pub address: u64,
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub checksum_ok: bool,
+}
+
+/// Generic Address Structure, how ACPI describes a register
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenericAddress {
+    /// 0 is system memory, 1 is system I/O
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+impl GenericAddress {
+    fn parse(phys: u64) -> GenericAddress {
+        GenericAddress {
+            address_space: read(phys),
+            bit_width: read(phys + 1),
+            bit_offset: read(phys + 2),
+            access_size: read(phys + 3),
+            address: read(phys + 4),
+        }
+    }
+}
+
+impl fmt::Display for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let space = match self.address_space {
+            ADDRESS_SPACE_MEMORY => "mem",
+            ADDRESS_SPACE_IO => "io",
+            _ => "other",
+        };
+        write!(f, "{} {:#x} ({} bits)", space, self.address, self.bit_width)
+    }
+}
+
+/// MADT type 0: a processor and its local APIC
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+impl LocalApic {
+    pub fn enabled(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    pub fn online_capable(&self) -> bool {
+        self.flags & 2 != 0
+    }
+}
+
+/// MADT type 1
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// MADT type 2: an ISA IRQ that is wired to a different GSI (usually the PIT)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    /// bits 0-1 polarity, bits 2-3 trigger mode
+    pub flags: u16,
+}
+
+/// MADT type 4: which LINT pin of a local APIC is wired to NMI
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalApicNmi {
+    /// 0xff means all processors
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub local_apics: [LocalApic; MAX_LOCAL_APICS],
+    pub local_apic_count: usize,
+    pub io_apics: [IoApic; MAX_IO_APICS],
+    pub io_apic_count: usize,
+    pub overrides: [InterruptOverride; MAX_OVERRIDES],
+    pub override_count: usize,
+    pub nmis: [LocalApicNmi; MAX_LAPIC_NMIS],
+    pub nmi_count: usize,
+}
+
+impl Madt {
+    pub fn local_apics(&self) -> &[LocalApic] {
+        &self.local_apics[..self.local_apic_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    pub fn nmis(&self) -> &[LocalApicNmi] {
+        &self.nmis[..self.nmi_count]
+    }
+
+    /// the GSI an ISA IRQ ends up on, taking overrides into account
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        self.overrides()
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map(|o| o.gsi)
+            .unwrap_or(irq as u32)
+    }
+
+    fn parse(phys: u64, len: u32) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(phys + 36) as u64,
+            flags: read(phys + 40),
+            local_apics: [LocalApic::default(); MAX_LOCAL_APICS],
+            local_apic_count: 0,
+            io_apics: [IoApic::default(); MAX_IO_APICS],
+            io_apic_count: 0,
+            overrides: [InterruptOverride::default(); MAX_OVERRIDES],
+            override_count: 0,
+            nmis: [LocalApicNmi::default(); MAX_LAPIC_NMIS],
+            nmi_count: 0,
+        };
+
+        let end = phys + len as u64;
+        let mut entry = phys + 44;
+        while entry + 2 <= end {
+            let kind: u8 = read(entry);
+            let entry_len: u8 = read(entry + 1);
+            if entry_len < 2 {
+                break; // broken table, don't loop forever
+            }
+            match kind {
+                0 if madt.local_apic_count < MAX_LOCAL_APICS => {
+                    madt.local_apics[madt.local_apic_count] = LocalApic {
+                        processor_id: read(entry + 2),
+                        apic_id: read(entry + 3),
+                        flags: read(entry + 4),
+                    };
+                    madt.local_apic_count += 1;
+                }
+                1 if madt.io_apic_count < MAX_IO_APICS => {
+                    madt.io_apics[madt.io_apic_count] = IoApic {
+                        id: read(entry + 2),
+                        address: read(entry + 4),
+                        gsi_base: read(entry + 8),
+                    };
+                    madt.io_apic_count += 1;
+                }
+                2 if madt.override_count < MAX_OVERRIDES => {
+                    madt.overrides[madt.override_count] = InterruptOverride {
+                        bus: read(entry + 2),
+                        source: read(entry + 3),
+                        gsi: read(entry + 4),
+                        flags: read(entry + 8),
+                    };
+                    madt.override_count += 1;
+                }
+                4 if madt.nmi_count < MAX_LAPIC_NMIS => {
+                    madt.nmis[madt.nmi_count] = LocalApicNmi {
+                        processor_id: read(entry + 2),
+                        flags: read(entry + 3),
+                        lint: read(entry + 5),
+                    };
+                    madt.nmi_count += 1;
+                }
+                5 => {
+                    // 64 bit override of the local APIC address
+                    madt.local_apic_address = read(entry + 4);
+                }
+                _ => {}
+            }
+            entry += entry_len as u64;
+        }
+        madt
+    }
+}
+
+/// the Fixed ACPI Description Table (signature FACP).
+/// fields that are past the end of an old (revision 1) FADT stay 0/None
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fadt {
+    pub revision: u8,
+    pub firmware_ctrl: u64,
+    pub dsdt: u64,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_control_length: u8,
+    /// CMOS register holding the century, 0 if there is none
+    pub century: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    pub reset_reg: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    fn parse(phys: u64, len: u32, revision: u8) -> Fadt {
+        // only read what the table is actually long enough to contain
+        let has = |offset: u32, size: u32| offset + size <= len;
+
+        let mut fadt = Fadt {
+            revision,
+            firmware_ctrl: read::<u32>(phys + 36) as u64,
+            dsdt: read::<u32>(phys + 40) as u64,
+            preferred_pm_profile: read(phys + 45),
+            sci_interrupt: read(phys + 46),
+            smi_command: read(phys + 48),
+            acpi_enable: read(phys + 52),
+            acpi_disable: read(phys + 53),
+            pm1a_event_block: read(phys + 56),
+            pm1b_event_block: read(phys + 60),
+            pm1a_control_block: read(phys + 64),
+            pm1b_control_block: read(phys + 68),
+            pm_timer_block: read(phys + 76),
+            pm1_control_length: read(phys + 89),
+            century: if has(108, 1) { read(phys + 108) } else { 0 },
+            ..Fadt::default()
+        };
+        if has(109, 2) {
+            fadt.boot_arch_flags = read(phys + 109);
+        }
+        if has(112, 4) {
+            fadt.flags = read(phys + 112);
+        }
+        if has(116, 13) && fadt.flags & FADT_RESET_REG_SUP != 0 {
+            fadt.reset_reg = Some(GenericAddress::parse(phys + 116));
+            fadt.reset_value = read(phys + 128);
+        }
+        if has(132, 8) {
+            let x_firmware_ctrl: u64 = read(phys + 132);
+            if x_firmware_ctrl != 0 {
+                fadt.firmware_ctrl = x_firmware_ctrl;
+            }
+        }
+        if has(140, 8) {
+            let x_dsdt: u64 = read(phys + 140);
+            if x_dsdt != 0 {
+                fadt.dsdt = x_dsdt;
+            }
+        }
+        fadt
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hpet {
+    pub hardware_rev_id: u8,
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    fn parse(phys: u64) -> Hpet {
+        let id: u32 = read(phys + 36);
+        Hpet {
+            hardware_rev_id: id as u8,
+            comparator_count: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            address: GenericAddress::parse(phys + 40),
+            hpet_number: read(phys + 52),
+            minimum_tick: read(phys + 53),
+        }
+    }
+}
+
+/// a PCI express enhanced configuration space window
+#[derive(Debug, Clone, Copy, Default)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    pub entries: [McfgEntry; MAX_MCFG_ENTRIES],
+    pub count: usize,
+}
+
+impl Mcfg {
+    pub fn entries(&self) -> &[McfgEntry] {
+        &self.entries[..self.count]
+    }
+
+    fn parse(phys: u64, len: u32) -> Mcfg {
+        let mut mcfg = Mcfg {
+            entries: [McfgEntry::default(); MAX_MCFG_ENTRIES],
+            count: 0,
+        };
+        // 8 reserved bytes follow the header, then 16 bytes per entry
+        let mut entry = phys + SDT_HEADER_LEN as u64 + 8;
+        while entry + 16 <= phys + len as u64 && mcfg.count < MAX_MCFG_ENTRIES {
+            mcfg.entries[mcfg.count] = McfgEntry {
+                base_address: read(entry),
+                segment: read(entry + 8),
+                start_bus: read(entry + 10),
+                end_bus: read(entry + 11),
+            };
+            mcfg.count += 1;
+            entry += 16;
+        }
+        mcfg
+    }
+}
+
+/// everything we found, filled in by `init`
+#[derive(Clone, Copy)]
+pub struct AcpiInfo {
+    pub rsdp_address: u64,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// `root_table` is the XSDT, a revision >= 2 RSDP can still leave its
+    /// address 0 and then we use the RSDT
+    pub use_xsdt: bool,
+    /// the XSDT if `use_xsdt`, the RSDT otherwise
+    pub root_table: u64,
+    pub tables: [Option<TableInfo>; MAX_TABLES],
+    pub table_count: usize,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl AcpiInfo {
+    pub fn tables(&self) -> impl Iterator<Item = &TableInfo> {
+        self.tables[..self.table_count].iter().flatten()
+    }
+
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<TableInfo> {
+        self.tables().find(|t| &t.signature == signature).copied()
+    }
+}
+
+/// look for "RSD PTR " on a 16 byte boundary in [start, end)
+fn scan_rsdp(start: u64, end: u64) -> Option<u64> {
+    let mut addr = start & !0xf;
+    while addr + 20 <= end {
+        if &read::<[u8; 8]>(addr) == b"RSD PTR " && checksum_ok(addr, 20) {
+            let revision: u8 = read(addr + 15);
+            if revision < 2 {
+                return Some(addr);
+            }
+            let length: u32 = read(addr + 20);
+            if checksum_ok(addr, length as usize) {
+                return Some(addr);
+            }
+        }
+        addr += 16;
+    }
+    None
+}
+
+/// the RSDP lives in the first KiB of the EBDA or in the BIOS ROM area
+pub fn find_rsdp() -> Option<u64> {
+    let ebda = (read::<u16>(EBDA_POINTER) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+fn table_info(phys: u64) -> TableInfo {
+    let length: u32 = read(phys + 4);
+    TableInfo {
+        signature: read::<[u8; 4]>(phys),
+        address: phys,
+        length,
+        revision: read(phys + 8),
+        oem_id: read::<[u8; 6]>(phys + 10),
+        checksum_ok: length as usize >= SDT_HEADER_LEN && checksum_ok(phys, length as usize),
+    }
+}
+
+/// find and decode the ACPI tables, has to run after `memory::init`
+pub fn init() -> bool {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return false,
+    };
+
+    let revision: u8 = read(rsdp + 15);
+    let use_xsdt = revision >= 2 && read::<u64>(rsdp + 24) != 0;
+    let root_table = if use_xsdt { read::<u64>(rsdp + 24) } else { read::<u32>(rsdp + 16) as u64 };
+
+    let mut info = AcpiInfo {
+        rsdp_address: rsdp,
+        revision,
+        oem_id: read::<[u8; 6]>(rsdp + 9),
+        use_xsdt,
+        root_table,
+        tables: [None; MAX_TABLES],
+        table_count: 0,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+
+    let root = table_info(root_table);
+    if !root.checksum_ok {
+        println_colored!(WARNING_COLOR, "ACPI: bad checksum on {}", Ascii(&root.signature));
+        return false;
+    }
+    let entry_size = if use_xsdt { 8 } else { 4 };
+    let count = (root.length as usize - SDT_HEADER_LEN) / entry_size;
+
+    for i in 0..count {
+        let entry = root_table + (SDT_HEADER_LEN + i * entry_size) as u64;
+        let phys = if use_xsdt { read::<u64>(entry) } else { read::<u32>(entry) as u64 };
+        if phys == 0 || info.table_count >= MAX_TABLES {
+            continue;
+        }
+        let table = table_info(phys);
+        info.tables[info.table_count] = Some(table);
+        info.table_count += 1;
+
+        if !table.checksum_ok {
+            continue;
+        }
+        match &table.signature {
+            b"APIC" => info.madt = Some(Madt::parse(phys, table.length)),
+            b"FACP" => info.fadt = Some(Fadt::parse(phys, table.length, table.revision)),
+            b"HPET" => info.hpet = Some(Hpet::parse(phys)),
+            b"MCFG" => info.mcfg = Some(Mcfg::parse(phys, table.length)),
+            _ => {}
+        }
+    }
+
+    *ACPI.lock() = Some(info);
+    true
+}
+
+/// a copy of what `init` found, None if there is no ACPI
+pub fn info() -> Option<AcpiInfo> {
+    *ACPI.lock()
+}
+
+pub fn madt() -> Option<Madt> {
+    ACPI.lock().as_ref().and_then(|i| i.madt)
+}
+
+pub fn fadt() -> Option<Fadt> {
+    ACPI.lock().as_ref().and_then(|i| i.fadt)
+}
+
+pub fn hpet() -> Option<Hpet> {
+    ACPI.lock().as_ref().and_then(|i| i.hpet)
+}
+
+pub fn mcfg() -> Option<Mcfg> {
+    ACPI.lock().as_ref().and_then(|i| i.mcfg)
+}
+
+/// the `acpi` shell command
+pub fn print_tables() {
+    let info = match info() {
+        Some(info) => info,
+        None => {
+            println!("no ACPI tables found");
+            return;
+        }
+    };
+
+    println!("RSDP at {:#x}, revision {}, OEM {}, {} at {:#x}",
+        info.rsdp_address, info.revision, Ascii(&info.oem_id),
+        if info.use_xsdt { "XSDT" } else { "RSDT" }, info.root_table);
+    for t in info.tables() {
+        println!("  {} at {:#010x} len {:5} rev {} OEM {} {}",
+            Ascii(&t.signature), t.address, t.length, t.revision, Ascii(&t.oem_id),
+            if t.checksum_ok { "" } else { "(bad checksum)" });
+    }
+
+    if let Some(madt) = info.madt {
+        println!("MADT: local APIC at {:#x}{}", madt.local_apic_address,
+            if madt.flags & MADT_PCAT_COMPAT != 0 { ", has 8259 PICs" } else { "" });
+        for cpu in madt.local_apics() {
+            println!("  CPU {} APIC id {} {}", cpu.processor_id, cpu.apic_id,
+                if cpu.enabled() { "enabled" } else if cpu.online_capable() { "online capable" } else { "disabled" });
+        }
+        for ioapic in madt.io_apics() {
+            println!("  IO APIC {} at {:#x} GSI base {}", ioapic.id, ioapic.address, ioapic.gsi_base);
+        }
+        for o in madt.overrides() {
+            println!("  override: IRQ {} -> GSI {} flags {:#x}", o.source, o.gsi, o.flags);
+        }
+        for nmi in madt.nmis() {
+            println!("  NMI: CPU {:#x} LINT{} flags {:#x}", nmi.processor_id, nmi.lint, nmi.flags);
+        }
+    }
+
+    if let Some(fadt) = info.fadt {
+        println!("FADT: rev {} DSDT {:#x} SCI {} SMI cmd {:#x} enable {:#x}",
+            fadt.revision, fadt.dsdt, fadt.sci_interrupt, fadt.smi_command, fadt.acpi_enable);
+        println!("  PM1a evt {:#x} cnt {:#x}, PM1b cnt {:#x}, PM timer {:#x}, century reg {:#x}",
+            fadt.pm1a_event_block, fadt.pm1a_control_block, fadt.pm1b_control_block,
+            fadt.pm_timer_block, fadt.century);
+        match fadt.reset_reg {
+            Some(reg) => println!("  reset register: {} value {:#x}", reg, fadt.reset_value),
+            None => println!("  no reset register"),
+        }
+    }
+
+    if let Some(hpet) = info.hpet {
+        println!("HPET {}: {}, {} comparators, {} bit counter, min tick {}",
+            hpet.hpet_number, hpet.address, hpet.comparator_count,
+            if hpet.counter_64bit { 64 } else { 32 }, hpet.minimum_tick);
+    }
+
+    if let Some(mcfg) = info.mcfg {
+        for e in mcfg.entries() {
+            println!("MCFG: segment {} buses {}-{} at {:#x}", e.segment, e.start_bus, e.end_bus, e.base_address);
+        }
+    }
+}
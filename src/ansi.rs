@@ -0,0 +1,160 @@
+//! ANSI/VT100 escape sequence parser
+//! https://vt100.net/emu/dec_ansi_parser
+//!
+//! splits a stream of characters into text, control characters and escape
+//! sequences, what they do is up to the console. the state machine is a cut
+//! down version of the DEC one: no OSC/DCS strings, intermediate bytes are
+//! accepted and dropped, and parameters past `MAX_PARAMS` are ignored.
+
+pub const MAX_PARAMS: usize = 16;
+
+const ESC: char = '\x1b';
+/// cancel a sequence half way
+const CAN: char = '\x18';
+const SUB: char = '\x1a';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// after ESC
+    Escape,
+    /// after ESC [
+    Csi,
+}
+
+/// a complete `ESC [ params final` sequence
+#[derive(Debug, Clone, Copy)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    /// the parameters started with '?' (DEC private modes)
+    pub private: bool,
+    /// the final character, which says what to do
+    pub action: char,
+}
+
+impl Csi {
+    const fn new() -> Csi {
+        Csi { params: [0; MAX_PARAMS], count: 0, private: false, action: '\0' }
+    }
+
+    /// parameter `i`, `default` if it's missing or 0 like for cursor movement
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    /// every parameter given, missing ones in between are 0
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// something to put on the screen
+    Print(char),
+    /// C0 control characters and DEL
+    Control(char),
+    /// `ESC final`, e.g. ESC 7 (save cursor)
+    Escape(char),
+    Csi(Csi),
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+    /// a digit or ';' was seen, so there is at least one parameter
+    has_params: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser { state: State::Ground, csi: Csi::new(), has_params: false }
+    }
+
+    /// drop a half parsed sequence
+    pub fn reset(&mut self) {
+        self.state = State::Ground;
+    }
+
+    /// feed one character, returns what it completed if anything
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        // these work the same in every state
+        match c {
+            ESC => {
+                self.state = State::Escape;
+                return None;
+            }
+            CAN | SUB => {
+                self.state = State::Ground;
+                return None;
+            }
+            // VT100s execute control characters in the middle of a sequence
+            '\0'..='\x1f' | '\x7f' => return Some(Action::Control(c)),
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => Some(Action::Print(c)),
+            State::Escape => match c {
+                '[' => {
+                    self.csi = Csi::new();
+                    self.has_params = false;
+                    self.state = State::Csi;
+                    None
+                }
+                // intermediates, as in ESC ( B to pick a character set
+                ' '..='/' => None,
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::Csi => match c {
+                '0'..='9' => {
+                    let i = self.csi.count;
+                    if i < MAX_PARAMS {
+                        let digit = c as u16 - '0' as u16;
+                        self.csi.params[i] = self.csi.params[i].saturating_mul(10).saturating_add(digit);
+                    }
+                    self.has_params = true;
+                    None
+                }
+                ';' => {
+                    self.csi.count += 1;
+                    self.has_params = true;
+                    None
+                }
+                '<'..='?' => {
+                    self.csi.private = true;
+                    None
+                }
+                ' '..='/' => None,
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    self.csi.count = if self.has_params {
+                        (self.csi.count + 1).min(MAX_PARAMS)
+                    } else {
+                        0
+                    };
+                    self.csi.action = c;
+                    Some(Action::Csi(self.csi))
+                }
+                // not part of a CSI sequence, give up on it
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
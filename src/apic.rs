@@ -0,0 +1,169 @@
+//! Local APIC (xAPIC, memory mapped)
+//! https://wiki.osdev.org/APIC
+//!
+//! the 8259 PICs still deliver the legacy IRQs to the boot CPU through
+//! LINT0, the local APIC is used for IPIs between CPUs.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+use crate::memory;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ADDR_MASK: u64 = 0xffff_f000;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// register offsets
+const REG_ID: u32 = 0x20;
+const REG_VERSION: u32 = 0x30;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SVR: u32 = 0xf0;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+
+const SVR_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// ICR bits
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_STATUS: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_OTHERS: u32 = 0b11 << 18;
+
+/// who an IPI goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDest {
+    /// one CPU, by APIC id
+    Apic(u8),
+    /// the sending CPU itself
+    SelfOnly,
+    /// every CPU including the sender
+    All,
+    /// every CPU but the sender
+    Others,
+}
+
+impl IpiDest {
+    /// destination APIC id and shorthand bits for the ICR
+    fn icr(self) -> (u8, u32) {
+        match self {
+            IpiDest::Apic(id) => (id, 0),
+            IpiDest::SelfOnly => (0, ICR_SHORTHAND_SELF),
+            IpiDest::All => (0, ICR_SHORTHAND_ALL),
+            IpiDest::Others => (0, ICR_SHORTHAND_OTHERS),
+        }
+    }
+}
+
+/// spurious interrupts must not be EOI'd, see `spurious_interrupt_handler`
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// virtual address of this machine's local APIC registers, 0 until `init`
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u32) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base + reg as u64) as *const u32) }
+}
+
+fn write(reg: u32, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base + reg as u64) as *mut u32, value) }
+}
+
+/// physical address of the local APIC from IA32_APIC_BASE
+pub fn physical_base() -> u64 {
+    unsafe { Msr::new(IA32_APIC_BASE_MSR).read() & APIC_BASE_ADDR_MASK }
+}
+
+pub fn is_initialized() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// map the registers and software-enable the boot CPU's local APIC.
+/// has to run after `memory::install`
+pub fn init() {
+    let phys = physical_base();
+    let virt = memory::map_mmio(PhysAddr::new(phys), 4096);
+    LAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+    enable();
+}
+
+/// every local APIC lives at the same physical address, so an AP only needs
+/// to switch its own one on
+pub fn init_ap() {
+    enable();
+}
+
+fn enable() {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+        let base = msr.read();
+        msr.write(base | APIC_BASE_GLOBAL_ENABLE);
+    }
+    write(REG_TPR, 0); // accept every priority
+    write(REG_SVR, SVR_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// APIC id of the CPU we're running on
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+pub fn version() -> u32 {
+    read(REG_VERSION) & 0xff
+}
+
+/// signal end of interrupt for anything delivered by the local APIC
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+fn wait_icr_idle() {
+    while read(REG_ICR_LOW) & ICR_DELIVERY_STATUS != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// write the interrupt command register, the high half has to go first
+/// because writing the low half sends the IPI
+fn send_icr(dest_apic_id: u8, low: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        wait_icr_idle();
+        write(REG_ESR, 0);
+        write(REG_ICR_HIGH, (dest_apic_id as u32) << 24);
+        write(REG_ICR_LOW, low);
+        wait_icr_idle();
+    });
+}
+
+/// INIT assert followed by INIT de-assert, as the MP spec wants for older APICs
+pub fn send_init(dest_apic_id: u8) {
+    send_icr(dest_apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL);
+    send_icr(dest_apic_id, ICR_DELIVERY_INIT | ICR_TRIGGER_LEVEL);
+}
+
+/// start-up IPI, the AP starts in real mode at `page << 12`
+pub fn send_startup(dest_apic_id: u8, page: u8) {
+    send_icr(dest_apic_id, ICR_DELIVERY_STARTUP | page as u32);
+}
+
+/// raise `vector` on the destination CPUs, whose handler has to call `eoi`
+pub fn send_ipi(dest: IpiDest, vector: u8) {
+    let (apic_id, shorthand) = dest.icr();
+    send_icr(apic_id, ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | shorthand | vector as u32);
+}
+
+/// NMI on the destination CPUs, it gets through even with interrupts disabled.
+/// the vector field is ignored for NMIs, they always end up at vector 2
+pub fn send_nmi(dest: IpiDest) {
+    let (apic_id, shorthand) = dest.icr();
+    send_icr(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT | shorthand);
+}
@@ -0,0 +1,62 @@
+//! frame pointer based stack walking
+//!
+//! the target JSON keeps frame pointers, so every Rust function starts with
+//! `push rbp; mov rbp, rsp` and `[rbp]` is the caller's RBP with the return
+//! address right above it. the walk stops at anything that doesn't look like
+//! a frame, and never touches unmapped memory.
+
+use x86_64::VirtAddr;
+use crate::memory;
+use crate::symbols::Symbol;
+use crate::println;
+
+const MAX_FRAMES: usize = 16;
+
+/// whether the 16 byte frame record at `addr` can be read. a corrupt frame
+/// pointer can be anything, right up to the end of the address space
+fn readable(addr: u64) -> bool {
+    let last = match addr.checked_add(15) {
+        Some(last) => last,
+        None => return false,
+    };
+    addr % 8 == 0
+        && VirtAddr::try_new(addr).is_ok()
+        && VirtAddr::try_new(last).is_ok()
+        && memory::is_mapped(VirtAddr::new(addr))
+        && memory::is_mapped(VirtAddr::new(last))
+}
+
+/// print `rip` and the return addresses of the frames above `rbp`
+pub fn print_from(rip: u64, mut rbp: u64) {
+    println!("backtrace:");
+    println!("  #0  {:#018x} {}", rip, Symbol(rip));
+    for i in 1..MAX_FRAMES {
+        if rbp == 0 || !readable(rbp) {
+            return;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            return;
+        }
+        // the return address is after the call, look up the call itself
+        println!("  #{:<2} {:#018x} {}", i, ret, Symbol(ret - 1));
+        // callers' frames are further up the stack
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+    println!("  ...");
+}
+
+/// backtrace of the caller
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    // our own frame is the first one, start with whoever called us
+    if readable(rbp) {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        print_from(ret.wrapping_sub(1), next);
+    }
+}
@@ -1,9 +1,23 @@
-use crate::vga_buffer::{BUFFER_WIDTH};
-use crate::strutils::{strcmpl};
-use crate::{println, OSINFO};
+use core::convert::TryFrom;
+use crate::vga_buffer::{MAX_WIDTH};
+use crate::strutils::{strcmpl, line_to_str, parse_num};
+use crate::{print, print_colored, println, OSINFO};
+use crate::rtc::{self, DateTime};
+use crate::memtools;
+use crate::debugreg::{self, Kind};
+use crate::watchdog;
+use crate::serial;
+use crate::framebuffer::{self, Framebuffer};
+use crate::text_mode::TextMode;
+use crate::gfx;
+use crate::vga_buffer::{self, CursorShape, PROMPT_COLOR};
 
 pub const PROMPT: char = '>';
 
+pub fn print_prompt() {
+	print_colored!(PROMPT_COLOR, "{}", PROMPT);
+}
+
 // pub fn bytes2chars(bs: &mut [char; BUFFER_WIDTH], chars: &mut [char; BUFFER_WIDTH]) {
 // 	for i in 0..BUFFER_WIDTH {
 // 		arr[i] = s[i];
@@ -12,7 +26,115 @@ pub const PROMPT: char = '>';
 
 const IA32_APIC_BASE_MSR:u32 = 0x1B;
 
-pub fn handle_cmd(input: &[char; BUFFER_WIDTH]) {
+/// split off a leading `-p` (physical address) flag, returns it and the next argument
+fn physical_flag<'a>(args: &mut core::str::SplitWhitespace<'a>) -> (bool, Option<&'a str>) {
+	match args.next() {
+		Some("-p") => (true, args.next()),
+		first => (false, first),
+	}
+}
+
+/// parse "YYYY-MM-DD" and "HH:MM:SS" into a date
+fn parse_datetime(date: &str, time: &str) -> Option<DateTime> {
+	let mut d = date.split('-');
+	let mut t = time.split(':');
+	let dt = DateTime {
+		year: d.next()?.parse().ok()?,
+		month: d.next()?.parse().ok()?,
+		day: d.next()?.parse().ok()?,
+		hour: t.next()?.parse().ok()?,
+		minute: t.next()?.parse().ok()?,
+		second: t.next()?.parse().ok()?,
+	};
+	if dt.is_valid() { Some(dt) } else { None }
+}
+
+/// switch to the graphics mode in the `[width height]` arguments, 1024x768 by
+/// default, as long as the console fits on it for afterwards
+fn graphics_mode(command: &str, args: &mut core::str::SplitWhitespace) -> Option<Framebuffer> {
+	let width = args.next().and_then(parse_num).unwrap_or(1024) as usize;
+	let height = args.next().and_then(parse_num).unwrap_or(768) as usize;
+	let (min_width, min_height) = vga_buffer::min_framebuffer_size();
+	if !framebuffer::is_available() {
+		println!("{}: no Bochs VBE graphics adapter (QEMU needs -vga std)", command);
+		None
+	} else if width < min_width || height < min_height {
+		println!("{}: the console needs at least {}x{}", command, min_width, min_height);
+		None
+	} else {
+		let fb = framebuffer::set_mode(width, height);
+		if fb.is_none() {
+			println!("{}: {}x{} not supported", command, width, height);
+		}
+		fb
+	}
+}
+
+/// `serial` without arguments: what's on COM1 to COM4
+fn list_serial_ports() {
+	for n in 1..=4 {
+		// not printed while it's locked, it may be the serial console
+		let (present, fifo, baud, irq, receiving, dropped) = {
+			let mut port = serial::port(n).unwrap().lock();
+			(port.is_present(), port.has_fifo(), port.baud(), port.irq(), port.receive_interrupt(), port.dropped())
+		};
+		if !present {
+			println!("COM{} IRQ{}: not present", n, irq);
+			continue;
+		}
+		println!("COM{} IRQ{}: {} 8N1{}{}{}, {} dropped", n, irq, baud,
+			if fifo { ", FIFO" } else { "" },
+			if receiving { ", receiving" } else { "" },
+			if serial::console() == Some(n) { ", console" } else { "" },
+			dropped);
+	}
+}
+
+/// `serial <n> [baud|read]`
+fn serial_port_cmd(n: usize, arg: Option<&str>) {
+	let port = match serial::port(n) {
+		Some(port) if port.lock().is_present() => port,
+		_ => {
+			println!("serial: no COM{}", n);
+			return;
+		}
+	};
+	if arg == Some("read") {
+		loop {
+			// the port may be the serial console, `print!` locks it too
+			let byte = port.lock().read_buffered();
+			let c = match byte {
+				Some(byte) => byte as char,
+				None => break,
+			};
+			print!("{}", if c.is_ascii_graphic() || c == ' ' || c == '\n' { c } else { '.' });
+		}
+		println!();
+		return;
+	}
+	let baud = match arg.map(parse_num) {
+		None => serial::DEFAULT_BAUD,
+		Some(Some(baud)) if baud <= u32::MAX as u64 => baud as u32,
+		Some(_) => {
+			println!("usage: serial [n [baud|read]]");
+			return;
+		}
+	};
+	if !port.lock().configure(baud) {
+		println!("serial: {} baud is not 115200 divided by 1 to 65535", baud);
+		return;
+	}
+	serial::enable_receive(n);
+	println!("COM{}: {} 8N1", n, baud);
+}
+
+pub fn handle_cmd(input: &[char]) {
+
+	let mut line_buf = [0u8; MAX_WIDTH];
+	let line = line_to_str(input, &mut line_buf);
+	// args[0] is the command itself
+	let mut args = line.split_whitespace();
+	args.next();
 
 	if strcmpl(input, "help", 4) {
 		println!(concat!(
@@ -22,6 +144,30 @@ pub fn handle_cmd(input: &[char; BUFFER_WIDTH]) {
     		"bootinfo: show boot info\n",
     		// "snph: trigger segment_not_present_handler\n",
     		"msr_acpi: get MSR IA32_APIC_BASE_MSR\n",
+    		"date: show wall clock time and uptime\n",
+    		"settime YYYY-MM-DD HH:MM:SS: set the RTC (UTC)\n",
+    		"rtcirq <rate|off>: RTC periodic interrupt, 32768 >> (rate-1) Hz\n",
+    		"acpi: list ACPI tables\n",
+    		"shutdown [qemu [fail]]: power off (qemu: isa-debug-exit)\n",
+    		"reboot: restart the machine\n",
+    		"restart: restart the shell and drivers without rebooting\n",
+    		"cpus: list CPUs and which came online\n",
+    		"disas <addr|symbol> [n]: disassemble n instructions\n",
+    		"peek [-p] <addr> [len]: read 1/2/4/8 bytes, longer is a hexdump\n",
+    		"poke [-p] <addr> <value> [width]: write 1/2/4/8 bytes (default 8)\n",
+    		"hexdump [-p] <addr> <len>: dump memory (-p: physical address)\n",
+    		"watch [-rw] <addr> [len]: break on writes (-rw: reads too), no args lists\n",
+    		"bp <addr|symbol>: break when the instruction at addr runs\n",
+    		"unwatch <n|all>: remove a watchpoint or breakpoint\n",
+    		"step [n]: single step n instructions after the next hit (0: off)\n",
+    		"gdb: wait for gdb on COM1 (target remote)\n",
+    		"serial [n [baud|read]]: list COM1-4, set up COMn (receiving on IRQ4/3) or show what it got\n",
+    		"serial console <n|off>: copy print output to COMn\n",
+    		"watchdog [on [secs]|off]: NMI lockup detection\n",
+    		"cursor <on|off|underline|half|block>: text cursor visibility and shape\n",
+    		"fbcon [width height]: move the console to a Bochs VBE framebuffer (1024x768)\n",
+    		"gfxdemo [width height]: animate the 2D drawing primitives, then fbcon\n",
+    		"mode [80x25|80x50|90x60]: VGA text mode (also leaves fbcon), no args shows the size\n",
     		"The QEMU escape key is Ctrl-Alt-G\n",
     		)
 		)
@@ -44,7 +190,10 @@ pub fn handle_cmd(input: &[char; BUFFER_WIDTH]) {
 	// "bootinfo".chars().count() will generate count at compile time, found out by RE
 	if strcmpl(input, "bootinfo", "bootinfo".chars().count()) {
 		// read memory regions
-		println!("{:?}", OSINFO.lock().bootinfo);
+		match OSINFO.lock().bootinfo {
+			Some(bootinfo) => println!("{:?}", bootinfo),
+			None => println!("no boot info yet"),
+		}
 
 	}
 
@@ -56,5 +205,255 @@ pub fn handle_cmd(input: &[char; BUFFER_WIDTH]) {
 
 	}
 
+	if strcmpl(input, "date", 4) {
+		let uptime = crate::time::uptime_ms();
+		println!("{} (unix {})", rtc::now(), rtc::unix_time());
+		println!("RTC:    {}", rtc::read_rtc());
+		println!("uptime: {}.{:03}s", uptime / 1000, uptime % 1000);
+		let freq = rtc::periodic_frequency();
+		if freq != 0 {
+			println!("RTC periodic interrupt: {}Hz, {} ticks", freq, rtc::periodic_ticks());
+		}
+	}
+
+	if strcmpl(input, "settime", 7) {
+		match (args.next(), args.next()) {
+			(Some(date), Some(time)) => match parse_datetime(date, time) {
+				Some(dt) => {
+					rtc::set_time(&dt);
+					println!("time set to {}", rtc::now());
+				}
+				None => println!("invalid date, expected YYYY-MM-DD HH:MM:SS"),
+			},
+			_ => println!("usage: settime YYYY-MM-DD HH:MM:SS"),
+		}
+	}
+
+	if strcmpl(input, "acpi", 4) {
+		crate::acpi::print_tables();
+	}
+
+	if strcmpl(input, "shutdown", 8) {
+		use crate::power::{self, QemuExitCode};
+
+		if args.next() == Some("qemu") {
+			let code = match args.next() {
+				Some("fail") => QemuExitCode::Failed,
+				_ => QemuExitCode::Success,
+			};
+			power::exit_qemu(code);
+			println!("not running in QEMU with isa-debug-exit");
+		} else {
+			power::shutdown();
+		}
+	}
+
+	if strcmpl(input, "reboot", 6) {
+		crate::power::reboot();
+	}
+
+	if strcmpl(input, "restart", 7) {
+		crate::soft_restart();
+	}
+
+	if strcmpl(input, "cpus", 4) {
+		crate::smp::print_cpus();
+	}
+
+	if strcmpl(input, "rtcirq", 6) {
+		match args.next() {
+			Some("off") => {
+				rtc::disable_periodic_interrupt();
+				println!("RTC periodic interrupt disabled");
+			}
+			Some(rate) => match parse_num(rate).map(u8::try_from) {
+				Some(Ok(rate)) if rtc::enable_periodic_interrupt(rate) => {
+					println!("RTC periodic interrupt at {}Hz", rtc::periodic_frequency());
+				}
+				_ => println!("rate has to be between 3 (8192Hz) and 15 (2Hz)"),
+			},
+			None => println!("usage: rtcirq <rate|off>"),
+		}
+	}
+
+	if strcmpl(input, "disas", 5) {
+		let addr = args.next().and_then(|a| parse_num(a).or_else(|| crate::symbols::address_of(a)));
+		let count = args.next().and_then(parse_num).unwrap_or(8).min(20);
+		match addr {
+			Some(addr) => crate::disas::print(addr, count as usize),
+			None => println!("usage: disas <addr|symbol> [n]"),
+		}
+	}
+
+	if strcmpl(input, "peek", 4) {
+		let (physical, addr) = physical_flag(&mut args);
+		let len = args.next().and_then(parse_num).unwrap_or(8);
+		match addr.and_then(parse_num) {
+			Some(addr) => {
+				let result = memtools::resolve(addr, len, physical).and_then(|virt| {
+					if matches!(len, 1 | 2 | 4 | 8) {
+						memtools::peek(virt, len).map(|value| println!("{:#x}: {:#x}", addr, value))
+					} else {
+						memtools::hexdump(virt, len)
+					}
+				});
+				if let Err(e) = result {
+					println!("peek {:#x}: {}", addr, e);
+				}
+			}
+			None => println!("usage: peek [-p] <addr> [len]"),
+		}
+	}
+
+	if strcmpl(input, "poke", 4) {
+		let (physical, addr) = physical_flag(&mut args);
+		let value = args.next().and_then(parse_num);
+		let width = args.next().and_then(parse_num).unwrap_or(8);
+		match (addr.and_then(parse_num), value) {
+			(Some(addr), Some(value)) => {
+				let result = memtools::resolve(addr, width, physical)
+					.and_then(|virt| memtools::poke(virt, value, width));
+				if let Err(e) = result {
+					println!("poke {:#x}: {}", addr, e);
+				}
+			}
+			_ => println!("usage: poke [-p] <addr> <value> [width]"),
+		}
+	}
+
+	if strcmpl(input, "hexdump", 7) {
+		let (physical, addr) = physical_flag(&mut args);
+		match (addr.and_then(parse_num), args.next().and_then(parse_num)) {
+			(Some(addr), Some(len)) => {
+				let result = memtools::resolve(addr, len, physical)
+					.and_then(|virt| memtools::hexdump(virt, len));
+				if let Err(e) = result {
+					println!("hexdump {:#x}: {}", addr, e);
+				}
+			}
+			_ => println!("usage: hexdump [-p] <addr> <len>"),
+		}
+	}
+
+	if strcmpl(input, "watch", 5) && !strcmpl(input, "watchdog", 8) {
+		let (kind, addr) = match args.next() {
+			Some("-rw") => (Kind::ReadWrite, args.next()),
+			first => (Kind::Write, first),
+		};
+		let len = args.next().and_then(parse_num).unwrap_or(1);
+		match addr {
+			None => debugreg::print_watchpoints(),
+			Some(addr) => match parse_num(addr) {
+				Some(addr) => match debugreg::set(addr, kind, len) {
+					Ok(slot) => println!("watchpoint {} set", slot),
+					Err(e) => println!("watch {:#x}: {}", addr, e),
+				},
+				None => println!("usage: watch [-rw] <addr> [len]"),
+			},
+		}
+	}
+
+	if strcmpl(input, "bp", 2) {
+		match args.next().and_then(|a| parse_num(a).or_else(|| crate::symbols::address_of(a))) {
+			Some(addr) => match debugreg::set(addr, Kind::Execute, 1) {
+				Ok(slot) => println!("breakpoint {} set", slot),
+				Err(e) => println!("bp {:#x}: {}", addr, e),
+			},
+			None => println!("usage: bp <addr|symbol>"),
+		}
+	}
+
+	if strcmpl(input, "unwatch", 7) {
+		match args.next() {
+			Some("all") => debugreg::clear_all(),
+			Some(slot) => match parse_num(slot).map(|slot| debugreg::clear(slot as usize)) {
+				Some(Ok(())) => {}
+				Some(Err(e)) => println!("unwatch {}: {}", slot, e),
+				None => println!("usage: unwatch <n|all>"),
+			},
+			None => println!("usage: unwatch <n|all>"),
+		}
+	}
+
+	if strcmpl(input, "step", 4) {
+		debugreg::step(args.next().and_then(parse_num).unwrap_or(1));
+	}
+
+	if strcmpl(input, "gdb", 3) {
+		crate::gdbstub::start();
+	}
+
+	if strcmpl(input, "serial", 6) {
+		match args.next() {
+			None => list_serial_ports(),
+			Some("console") => match args.next() {
+				Some("off") => serial::set_console(None),
+				Some(n) => match parse_num(n).and_then(|n| serial::port(n as usize).map(|_| n as usize)) {
+					Some(n) => serial::set_console(Some(n)),
+					None => println!("usage: serial console <n|off>"),
+				},
+				None => println!("usage: serial console <n|off>"),
+			},
+			Some(n) => match parse_num(n) {
+				Some(n) => serial_port_cmd(n as usize, args.next()),
+				None => println!("usage: serial [n [baud|read]]"),
+			},
+		}
+	}
+
+	if strcmpl(input, "watchdog", 8) {
+		match args.next() {
+			None => watchdog::print_status(),
+			Some("on") => {
+				let secs = args.next().and_then(parse_num).unwrap_or(watchdog::DEFAULT_TIMEOUT_SECS);
+				if !(1..=watchdog::MAX_TIMEOUT_SECS).contains(&secs) {
+					println!("watchdog: timeout has to be 1 to {}s", watchdog::MAX_TIMEOUT_SECS);
+				} else if !watchdog::enable(secs) {
+					println!("watchdog: no I/O APIC");
+				}
+			}
+			Some("off") => watchdog::disable(),
+			Some(_) => println!("usage: watchdog [on [secs]|off]"),
+		}
+	}
+
+	if strcmpl(input, "cursor", 6) {
+		match args.next() {
+			Some("on") => vga_buffer::show_cursor(true),
+			Some("off") => vga_buffer::show_cursor(false),
+			Some("underline") => vga_buffer::set_cursor_shape(CursorShape::Underline),
+			Some("half") => vga_buffer::set_cursor_shape(CursorShape::HalfBlock),
+			Some("block") => vga_buffer::set_cursor_shape(CursorShape::Block),
+			_ => println!("usage: cursor <on|off|underline|half|block>"),
+		}
+	}
+
+	if strcmpl(input, "fbcon", 5) {
+		if let Some(fb) = graphics_mode("fbcon", &mut args) {
+			vga_buffer::use_framebuffer(fb);
+		}
+	}
+
+	if strcmpl(input, "gfxdemo", 7) {
+		if let Some(mut fb) = graphics_mode("gfxdemo", &mut args) {
+			gfx::demo(&mut fb);
+			// stays in graphics, `mode` goes back to text
+			vga_buffer::use_framebuffer(fb);
+		}
+	}
+
+	if strcmpl(input, "mode", 4) {
+		match args.next() {
+			None => {
+				let (width, height) = vga_buffer::size();
+				println!("{}x{}", width, height);
+			}
+			Some(arg) => match TextMode::parse(arg) {
+				Some(mode) => vga_buffer::set_text_mode(mode),
+				None => println!("usage: mode [80x25|80x50|90x60]"),
+			},
+		}
+	}
+
 	
 }
\ No newline at end of file
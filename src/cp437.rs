@@ -0,0 +1,68 @@
+//! code page 437, the character set of the VGA text mode font
+//! https://en.wikipedia.org/wiki/Code_page_437
+//!
+//! ASCII is itself, the rest is box drawing, blocks, accented Latin, some
+//! Greek and math, and the symbols the BIOS font has in place of the control
+//! characters (smileys, card suits, arrows).
+
+/// what characters code page 437 doesn't have turn into: ■
+pub const FALLBACK: u8 = 0xfe;
+
+/// 0x01-0x1f, the glyphs in place of the control characters
+const LOW: [char; 31] = [
+          '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// 0x7f
+const HOUSE: char = '⌂';
+
+/// 0x80-0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// characters that look the same as one in the table
+const ALIASES: [(char, u8); 6] = [
+    ('β', 0xe1), // the font's ß doubles as beta
+    ('μ', 0xe6), // Greek mu vs the micro sign
+    ('∑', 0xe4),
+    ('∈', 0xee),
+    ('ϕ', 0xed),
+    ('\u{2126}', 0xea), // the ohm sign
+];
+
+/// the code page 437 byte for `c`, if the font has it
+pub fn from_char(c: char) -> Option<u8> {
+    if (' '..='~').contains(&c) {
+        return Some(c as u8);
+    }
+    if c == HOUSE {
+        return Some(0x7f);
+    }
+    if let Some(i) = LOW.iter().position(|&l| l == c) {
+        return Some(i as u8 + 0x01);
+    }
+    if let Some(i) = HIGH.iter().position(|&h| h == c) {
+        return Some(i as u8 + 0x80);
+    }
+    ALIASES.iter().find(|&&(alias, _)| alias == c).map(|&(_, byte)| byte)
+}
+
+/// the character a code page 437 byte shows, NUL is itself
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x00 => '\0',
+        0x01..=0x1f => LOW[byte as usize - 0x01],
+        0x7f => HOUSE,
+        0x80..=0xff => HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
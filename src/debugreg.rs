@@ -0,0 +1,290 @@
+//! hardware breakpoints, watchpoints and single stepping
+//! https://wiki.osdev.org/CPU_Registers_x86-64#Debug_Registers
+//!
+//! DR0-DR3 hold up to four addresses, DR7 says what to watch at each of them
+//! (execution, writes, reads and writes) and how many bytes. when one fires
+//! the CPU raises #DB and DR6 says which one it was. setting TF in RFLAGS
+//! raises #DB after every instruction instead.
+//!
+//! the debug registers are per CPU. `WATCHPOINTS` is what all of them should
+//! have, every CPU loads it when it comes online and when it changes.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber,
+    Dr0, Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags, Dr7Value,
+};
+use x86_64::VirtAddr;
+use crate::{apic, smp};
+use crate::exceptions::TrapFrame;
+use crate::lock::Mutex;
+use crate::symbols::Symbol;
+use crate::{disas, print, println};
+
+/// tells the other CPUs to reload their debug registers
+pub const RELOAD_VECTOR: u8 = 0xf1;
+
+pub const SLOTS: usize = 4;
+
+const RFLAGS_TF: u64 = 1 << 8;
+/// skip instruction breakpoints for one instruction, so returning to the
+/// breakpoint doesn't hit it again right away
+const RFLAGS_RF: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+impl Kind {
+    fn condition(self) -> BreakpointCondition {
+        match self {
+            Kind::Execute => BreakpointCondition::InstructionExecution,
+            Kind::Write => BreakpointCondition::DataWrites,
+            Kind::ReadWrite => BreakpointCondition::DataReadsWrites,
+        }
+    }
+
+    fn from_condition(condition: BreakpointCondition) -> Kind {
+        match condition {
+            BreakpointCondition::InstructionExecution => Kind::Execute,
+            BreakpointCondition::DataWrites => Kind::Write,
+            // I/O breakpoints are never set
+            _ => Kind::ReadWrite,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Execute => "execute",
+            Kind::Write => "write",
+            Kind::ReadWrite => "read/write",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub kind: Kind,
+    /// 1, 2, 4 or 8 bytes, always 1 for `Kind::Execute`
+    pub len: u64,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Execute => write!(f, "execute at {:#x} {}", self.addr, Symbol(self.addr)),
+            kind => write!(f, "{} {} bytes at {:#x}", kind, self.len, self.addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// all four debug registers are in use
+    NoFreeSlot,
+    NoSuchSlot,
+    /// not a valid (canonical) address
+    BadAddress,
+    /// only 1, 2, 4 and 8 bytes
+    BadLength,
+    /// the watched range has to be aligned to its length
+    Unaligned,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            WatchError::NoFreeSlot => "all 4 debug registers are in use",
+            WatchError::NoSuchSlot => "no such watchpoint",
+            WatchError::BadAddress => "not a valid address",
+            WatchError::BadLength => "length has to be 1, 2, 4 or 8",
+            WatchError::Unaligned => "address isn't aligned to the length",
+        })
+    }
+}
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; SLOTS]> = Mutex::new("WATCHPOINTS", [None; SLOTS]);
+
+/// instructions left to single step after the next breakpoint
+static STEPS: AtomicU64 = AtomicU64::new(0);
+
+fn number(slot: usize) -> DebugAddressRegisterNumber {
+    DebugAddressRegisterNumber::new(slot as u8).unwrap()
+}
+
+fn read_addr(slot: usize) -> u64 {
+    match slot {
+        0 => Dr0::read(),
+        1 => Dr1::read(),
+        2 => Dr2::read(),
+        _ => Dr3::read(),
+    }
+}
+
+fn write_addr(slot: usize, addr: u64) {
+    match slot {
+        0 => Dr0::write(addr),
+        1 => Dr1::write(addr),
+        2 => Dr2::write(addr),
+        _ => Dr3::write(addr),
+    }
+}
+
+/// clear the sticky status bits, the CPU never does
+fn clear_dr6() {
+    let status = Dr6Flags::TRAP | Dr6Flags::ACCESS_DETECTED | Dr6Flags::STEP | Dr6Flags::SWITCH;
+    let value = Dr6::read_raw() & !status.bits();
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// program this CPU's debug registers from `WATCHPOINTS`
+pub fn load() {
+    let watchpoints = *WATCHPOINTS.lock();
+    let mut dr7 = Dr7Value::from(Dr7Flags::GLOBAL_EXACT_BREAKPOINT_ENABLE);
+    for (slot, watchpoint) in watchpoints.iter().enumerate() {
+        if let Some(w) = watchpoint {
+            let n = number(slot);
+            write_addr(slot, w.addr);
+            dr7.set_condition(n, w.kind.condition());
+            dr7.set_size(n, BreakpointSize::new(w.len as usize).unwrap());
+            dr7.insert_flags(Dr7Flags::global_breakpoint_enable(n));
+        }
+    }
+    Dr7::write(dr7);
+}
+
+/// load the changed `WATCHPOINTS` here and on every other CPU
+fn reload_all() {
+    load();
+    if apic::is_initialized() {
+        smp::send_ipi(smp::other_online_cpus(), RELOAD_VECTOR);
+    }
+}
+
+/// watch `len` bytes at `addr`, returns the debug register used
+pub fn set(addr: u64, kind: Kind, len: u64) -> Result<usize, WatchError> {
+    VirtAddr::try_new(addr).map_err(|_| WatchError::BadAddress)?;
+    if kind == Kind::Execute && len != 1 {
+        return Err(WatchError::BadLength);
+    }
+    if !matches!(len, 1 | 2 | 4 | 8) {
+        return Err(WatchError::BadLength);
+    }
+    if addr % len != 0 {
+        return Err(WatchError::Unaligned);
+    }
+
+    let slot = {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let slot = watchpoints.iter().position(Option::is_none).ok_or(WatchError::NoFreeSlot)?;
+        watchpoints[slot] = Some(Watchpoint { addr, kind, len });
+        slot
+    };
+    reload_all();
+    Ok(slot)
+}
+
+pub fn clear(slot: usize) -> Result<(), WatchError> {
+    {
+        let mut watchpoints = WATCHPOINTS.lock();
+        match watchpoints.get_mut(slot) {
+            Some(w @ Some(_)) => *w = None,
+            _ => return Err(WatchError::NoSuchSlot),
+        }
+    }
+    reload_all();
+    Ok(())
+}
+
+pub fn clear_all() {
+    *WATCHPOINTS.lock() = [None; SLOTS];
+    reload_all();
+}
+
+pub fn print_watchpoints() {
+    let watchpoints = *WATCHPOINTS.lock();
+    if watchpoints.iter().all(Option::is_none) {
+        println!("no watchpoints");
+    }
+    for (slot, watchpoint) in watchpoints.iter().enumerate() {
+        if let Some(w) = watchpoint {
+            println!("{}: {}", slot, w);
+        }
+    }
+    let steps = STEPS.load(Ordering::Relaxed);
+    if steps > 0 {
+        println!("stepping {} instructions after the next hit", steps);
+    }
+}
+
+/// single step `count` instructions after the next breakpoint or watchpoint
+/// hit, 0 stops stepping
+pub fn step(count: u64) {
+    STEPS.store(count, Ordering::Relaxed);
+}
+
+/// set or clear TF in `frame` depending on whether there are steps left
+pub fn continue_stepping(frame: &mut TrapFrame) {
+    let left = STEPS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    if left.is_ok() {
+        frame.rflags |= RFLAGS_TF;
+    } else {
+        frame.rflags &= !RFLAGS_TF;
+    }
+}
+
+/// let the code behind `frame` go on without hitting the same #DB again
+pub fn acknowledge(frame: &mut TrapFrame) {
+    clear_dr6();
+    frame.rflags |= RFLAGS_RF;
+}
+
+/// report a #DB from the decoded DR6, in place of the full exception report
+pub fn handle_debug(frame: &mut TrapFrame) {
+    let status = Dr6::read();
+    let dr7 = Dr7::read();
+
+    for slot in 0..SLOTS {
+        let n = number(slot);
+        // DR6 may also flag hits of disabled slots whose condition matched
+        if !status.contains(Dr6Flags::trap(n))
+            || !dr7.flags().contains(Dr7Flags::global_breakpoint_enable(n))
+        {
+            continue;
+        }
+        let w = Watchpoint {
+            addr: read_addr(slot),
+            kind: Kind::from_condition(dr7.condition(n)),
+            len: match dr7.size(n) {
+                BreakpointSize::Length1B => 1,
+                BreakpointSize::Length2B => 2,
+                BreakpointSize::Length4B => 4,
+                BreakpointSize::Length8B => 8,
+            },
+        };
+        if w.kind == Kind::Execute {
+            println!("DEBUG: breakpoint {} hit on CPU {}: {}", slot, crate::percpu::cpu_id(), w);
+        } else {
+            // data watchpoints are traps, RIP is already past the access
+            println!("DEBUG: watchpoint {} hit on CPU {}: {}", slot, crate::percpu::cpu_id(), w);
+            println!("after {:#x} {}", frame.rip, Symbol(frame.rip));
+        }
+    }
+    if status.contains(Dr6Flags::ACCESS_DETECTED) {
+        println!("DEBUG: debug register access at {:#x} {}", frame.rip, Symbol(frame.rip));
+    }
+    if status.contains(Dr6Flags::STEP) {
+        print!("step: ");
+    }
+    disas::print(frame.rip, 1);
+
+    acknowledge(frame);
+    continue_stepping(frame);
+}
@@ -0,0 +1,771 @@
+//! x86-64 instruction decoder for fault reports and the `disas` command
+//!
+//! covers what the kernel itself is mostly made of: the legacy prefixes and
+//! REX, ModRM/SIB addressing, MOV, the ALU groups, shifts, push/pop, jumps,
+//! calls and the system instructions (rdmsr, mov crN, lgdt, ...). no SSE or
+//! x87, the kernel is built without them. anything it doesn't know decodes as
+//! a one byte `(bad)` so a listing can carry on. output is Intel syntax.
+
+use core::fmt::{self, Write};
+use x86_64::VirtAddr;
+use crate::memory;
+use crate::strutils::StrBuf;
+use crate::symbols::Symbol;
+use crate::println;
+
+/// longest possible instruction
+pub const MAX_LEN: usize = 15;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    fn ptr(self) -> &'static str {
+        match self {
+            Size::Byte => "byte ptr ",
+            Size::Word => "word ptr ",
+            Size::Dword => "dword ptr ",
+            Size::Qword => "qword ptr ",
+        }
+    }
+
+    /// suffix of the string instructions
+    fn suffix(self) -> &'static str {
+        match self {
+            Size::Byte => "b",
+            Size::Word => "w",
+            Size::Dword => "d",
+            Size::Qword => "q",
+        }
+    }
+}
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+    "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+const REGS16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+    "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w",
+];
+/// with a REX prefix 4-7 are the low bytes of rsp, rbp, rsi and rdi
+const REGS8_REX: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+const REGS8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8, Size),
+    /// `size` is None for operands that are only an address (lea, lgdt, ...)
+    Mem { base: Option<u8>, index: Option<(u8, u8)>, disp: i64, rip: bool, size: Option<Size> },
+    Imm(i64),
+    /// branch target
+    Rel(u64),
+    /// cl for shifts and dx for port I/O
+    Fixed(&'static str),
+    Cr(u8),
+    Dr(u8),
+}
+
+/// one decoded instruction before formatting
+struct Decoded {
+    prefix: &'static str,
+    mnemonic: &'static str,
+    /// condition code or size letter glued to the mnemonic
+    suffix: &'static str,
+    operands: [Option<Operand>; 3],
+}
+
+fn ins(mnemonic: &'static str, ops: &[Operand]) -> Decoded {
+    let mut operands = [None; 3];
+    for (slot, op) in operands.iter_mut().zip(ops) {
+        *slot = Some(*op);
+    }
+    Decoded { prefix: "", mnemonic, suffix: "", operands }
+}
+
+fn ins_suffix(mnemonic: &'static str, suffix: &'static str, ops: &[Operand]) -> Decoded {
+    Decoded { suffix, ..ins(mnemonic, ops) }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    addr: u64,
+    rex: u8,
+    opsize16: bool,
+    addr32: bool,
+    rep: bool,
+    repne: bool,
+    lock: bool,
+    segment: Option<&'static str>,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        if self.pos >= MAX_LEN {
+            return None;
+        }
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn imm8(&mut self) -> Option<i64> {
+        Some(self.byte()? as i8 as i64)
+    }
+
+    fn imm16(&mut self) -> Option<i64> {
+        Some(u16::from_le_bytes([self.byte()?, self.byte()?]) as i16 as i64)
+    }
+
+    fn imm32(&mut self) -> Option<i64> {
+        let b = [self.byte()?, self.byte()?, self.byte()?, self.byte()?];
+        Some(i32::from_le_bytes(b) as i64)
+    }
+
+    fn imm64(&mut self) -> Option<i64> {
+        let lo = self.imm32()? as u32 as u64;
+        let hi = self.imm32()? as u32 as u64;
+        Some((hi << 32 | lo) as i64)
+    }
+
+    /// immediate of an operation of `size`, 64 bit operations take 32 bit
+    /// immediates that get sign extended. byte operations show theirs unsigned
+    fn imm(&mut self, size: Size) -> Option<i64> {
+        match size {
+            Size::Byte => Some(self.byte()? as i64),
+            Size::Word => self.imm16(),
+            Size::Dword | Size::Qword => self.imm32(),
+        }
+    }
+
+    fn rel8(&mut self) -> Option<Operand> {
+        let disp = self.imm8()?;
+        Some(Operand::Rel(self.next_ip().wrapping_add(disp as u64)))
+    }
+
+    fn rel32(&mut self) -> Option<Operand> {
+        let disp = self.imm32()?;
+        Some(Operand::Rel(self.next_ip().wrapping_add(disp as u64)))
+    }
+
+    fn next_ip(&self) -> u64 {
+        self.addr.wrapping_add(self.pos as u64)
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    fn rex_r(&self) -> u8 {
+        (self.rex >> 2 & 1) << 3
+    }
+
+    fn rex_x(&self) -> u8 {
+        (self.rex >> 1 & 1) << 3
+    }
+
+    fn rex_b(&self) -> u8 {
+        (self.rex & 1) << 3
+    }
+
+    /// operand size of instructions that default to 32 bit
+    fn osize(&self) -> Size {
+        if self.rex_w() {
+            Size::Qword
+        } else if self.opsize16 {
+            Size::Word
+        } else {
+            Size::Dword
+        }
+    }
+
+    /// operand size of push, pop and indirect branches, which default to 64 bit
+    fn stack_size(&self) -> Size {
+        if self.opsize16 { Size::Word } else { Size::Qword }
+    }
+
+    /// decode a ModRM byte (and SIB and displacement), returns the reg field
+    /// and the r/m operand
+    fn modrm(&mut self, size: Size) -> Option<(u8, Operand)> {
+        let modrm = self.byte()?;
+        let md = modrm >> 6;
+        let reg = (modrm >> 3 & 7) | self.rex_r();
+        let rm = modrm & 7;
+        if md == 3 {
+            return Some((reg, Operand::Reg(rm | self.rex_b(), size)));
+        }
+
+        let mut base = None;
+        let mut index = None;
+        let mut disp = 0;
+        let mut rip = false;
+        if rm == 4 {
+            let sib = self.byte()?;
+            let idx = (sib >> 3 & 7) | self.rex_x();
+            if idx != 4 {
+                index = Some((idx, 1 << (sib >> 6)));
+            }
+            if sib & 7 == 5 && md == 0 {
+                disp = self.imm32()?;
+            } else {
+                base = Some((sib & 7) | self.rex_b());
+            }
+        } else if rm == 5 && md == 0 {
+            rip = true;
+            disp = self.imm32()?;
+        } else {
+            base = Some(rm | self.rex_b());
+        }
+        match md {
+            1 => disp = self.imm8()?,
+            2 => disp = self.imm32()?,
+            _ => {}
+        }
+        Some((reg, Operand::Mem { base, index, disp, rip, size: Some(size) }))
+    }
+
+    /// a ModRM operand that has to be memory and has no size of its own
+    fn modrm_address(&mut self) -> Option<(u8, Operand)> {
+        match self.modrm(Size::Qword)? {
+            (reg, Operand::Mem { base, index, disp, rip, .. }) => {
+                Some((reg, Operand::Mem { base, index, disp, rip, size: None }))
+            }
+            _ => None,
+        }
+    }
+
+    /// reg and r/m fields of a ModRM byte without any memory decoding,
+    /// for the instructions that ignore the mod field
+    fn modrm_regs(&mut self) -> Option<(u8, u8)> {
+        let modrm = self.byte()?;
+        Some(((modrm >> 3 & 7) | self.rex_r(), (modrm & 7) | self.rex_b()))
+    }
+
+    fn prefixes(&mut self) -> Option<u8> {
+        loop {
+            let b = self.byte()?;
+            match b {
+                0x66 => self.opsize16 = true,
+                0x67 => self.addr32 = true,
+                0xf0 => self.lock = true,
+                0xf2 => self.repne = true,
+                0xf3 => self.rep = true,
+                0x64 => self.segment = Some("fs:"),
+                0x65 => self.segment = Some("gs:"),
+                // ignored in long mode (or branch hints)
+                0x26 | 0x2e | 0x36 | 0x3e => {}
+                // REX has to come right before the opcode
+                0x40..=0x4f => {
+                    self.rex = b;
+                    return self.byte();
+                }
+                _ => return Some(b),
+            }
+        }
+    }
+
+    fn decode(&mut self) -> Option<Decoded> {
+        let op = self.prefixes()?;
+        let mut d = match op {
+            0x0f => self.decode_0f()?,
+            0x00..=0x3f if op & 7 < 6 => {
+                let name = ALU[(op >> 3) as usize];
+                let s = self.osize();
+                match op & 7 {
+                    0 => {
+                        let (r, rm) = self.modrm(Size::Byte)?;
+                        ins(name, &[rm, Operand::Reg(r, Size::Byte)])
+                    }
+                    1 => {
+                        let (r, rm) = self.modrm(s)?;
+                        ins(name, &[rm, Operand::Reg(r, s)])
+                    }
+                    2 => {
+                        let (r, rm) = self.modrm(Size::Byte)?;
+                        ins(name, &[Operand::Reg(r, Size::Byte), rm])
+                    }
+                    3 => {
+                        let (r, rm) = self.modrm(s)?;
+                        ins(name, &[Operand::Reg(r, s), rm])
+                    }
+                    4 => ins(name, &[Operand::Reg(0, Size::Byte), Operand::Imm(self.imm(Size::Byte)?)]),
+                    _ => ins(name, &[Operand::Reg(0, s), Operand::Imm(self.imm(s)?)]),
+                }
+            }
+            0x50..=0x57 => ins("push", &[Operand::Reg((op & 7) | self.rex_b(), self.stack_size())]),
+            0x58..=0x5f => ins("pop", &[Operand::Reg((op & 7) | self.rex_b(), self.stack_size())]),
+            0x63 => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(Size::Dword)?;
+                ins("movsxd", &[Operand::Reg(r, s), rm])
+            }
+            0x68 => ins("push", &[Operand::Imm(self.imm32()?)]),
+            0x6a => ins("push", &[Operand::Imm(self.imm8()?)]),
+            0x69 | 0x6b => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(s)?;
+                let imm = if op == 0x69 { self.imm(s)? } else { self.imm8()? };
+                ins("imul", &[Operand::Reg(r, s), rm, Operand::Imm(imm)])
+            }
+            0x70..=0x7f => ins_suffix("j", CONDITIONS[(op & 0xf) as usize], &[self.rel8()?]),
+            0x80 | 0x81 | 0x83 => {
+                let s = if op == 0x80 { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                let imm = if op == 0x83 { self.imm8()? } else { self.imm(s)? };
+                ins(ALU[(r & 7) as usize], &[rm, Operand::Imm(imm)])
+            }
+            0x84..=0x89 => {
+                let s = if op & 1 == 0 { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                let name = match op {
+                    0x84 | 0x85 => "test",
+                    0x86 | 0x87 => "xchg",
+                    _ => "mov",
+                };
+                ins(name, &[rm, Operand::Reg(r, s)])
+            }
+            0x8a | 0x8b => {
+                let s = if op == 0x8a { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                ins("mov", &[Operand::Reg(r, s), rm])
+            }
+            0x8d => {
+                let s = self.osize();
+                let (r, rm) = self.modrm_address()?;
+                ins("lea", &[Operand::Reg(r, s), rm])
+            }
+            0x8f => match self.modrm(self.stack_size())? {
+                (r, rm) if r & 7 == 0 => ins("pop", &[rm]),
+                _ => return None,
+            },
+            0x90 if self.rep => ins("pause", &[]),
+            0x90 if self.rex_b() == 0 => ins("nop", &[]),
+            0x90..=0x97 => {
+                let s = self.osize();
+                ins("xchg", &[Operand::Reg((op & 7) | self.rex_b(), s), Operand::Reg(0, s)])
+            }
+            0x98 => ins(match self.osize() { Size::Qword => "cdqe", Size::Word => "cbw", _ => "cwde" }, &[]),
+            0x99 => ins(match self.osize() { Size::Qword => "cqo", Size::Word => "cwd", _ => "cdq" }, &[]),
+            0xa4..=0xa7 | 0xaa..=0xaf => {
+                let s = if op & 1 == 0 { Size::Byte } else { self.osize() };
+                let name = match op & !1 {
+                    0xa4 => "movs",
+                    0xa6 => "cmps",
+                    0xaa => "stos",
+                    0xac => "lods",
+                    _ => "scas",
+                };
+                let mut d = ins_suffix(name, s.suffix(), &[]);
+                // cmps and scas stop on (in)equality, the others just repeat
+                d.prefix = match (self.rep, self.repne, op & !1) {
+                    (true, _, 0xa6) | (true, _, 0xae) => "repe ",
+                    (true, _, _) => "rep ",
+                    (_, true, _) => "repne ",
+                    _ => "",
+                };
+                d
+            }
+            0xa8 => ins("test", &[Operand::Reg(0, Size::Byte), Operand::Imm(self.imm(Size::Byte)?)]),
+            0xa9 => {
+                let s = self.osize();
+                ins("test", &[Operand::Reg(0, s), Operand::Imm(self.imm(s)?)])
+            }
+            0xb0..=0xb7 => {
+                let reg = (op & 7) | self.rex_b();
+                ins("mov", &[Operand::Reg(reg, Size::Byte), Operand::Imm(self.byte()? as i64)])
+            }
+            0xb8..=0xbf => {
+                let s = self.osize();
+                let imm = if s == Size::Qword { self.imm64()? } else { self.imm(s)? };
+                ins("mov", &[Operand::Reg((op & 7) | self.rex_b(), s), Operand::Imm(imm)])
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let s = if op & 1 == 0 { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                let count = match op {
+                    0xc0 | 0xc1 => Operand::Imm(self.byte()? as i64),
+                    0xd0 | 0xd1 => Operand::Imm(1),
+                    _ => Operand::Fixed("cl"),
+                };
+                ins(SHIFTS[(r & 7) as usize], &[rm, count])
+            }
+            0xc2 => ins("ret", &[Operand::Imm(self.imm16()? as u16 as i64)]),
+            0xc3 => ins("ret", &[]),
+            0xc6 | 0xc7 => {
+                let s = if op == 0xc6 { Size::Byte } else { self.osize() };
+                match self.modrm(s)? {
+                    (r, rm) if r & 7 == 0 => ins("mov", &[rm, Operand::Imm(self.imm(s)?)]),
+                    _ => return None,
+                }
+            }
+            0xc9 => ins("leave", &[]),
+            0xcc => ins("int3", &[]),
+            0xcd => ins("int", &[Operand::Imm(self.byte()? as i64)]),
+            0xcf => ins(if self.rex_w() { "iretq" } else { "iretd" }, &[]),
+            0xe4 | 0xe5 => {
+                let s = if op == 0xe4 { Size::Byte } else if self.opsize16 { Size::Word } else { Size::Dword };
+                ins("in", &[Operand::Reg(0, s), Operand::Imm(self.byte()? as i64)])
+            }
+            0xe6 | 0xe7 => {
+                let s = if op == 0xe6 { Size::Byte } else if self.opsize16 { Size::Word } else { Size::Dword };
+                ins("out", &[Operand::Imm(self.byte()? as i64), Operand::Reg(0, s)])
+            }
+            0xec | 0xed => {
+                let s = if op == 0xec { Size::Byte } else if self.opsize16 { Size::Word } else { Size::Dword };
+                ins("in", &[Operand::Reg(0, s), Operand::Fixed("dx")])
+            }
+            0xee | 0xef => {
+                let s = if op == 0xee { Size::Byte } else if self.opsize16 { Size::Word } else { Size::Dword };
+                ins("out", &[Operand::Fixed("dx"), Operand::Reg(0, s)])
+            }
+            0xe8 => ins("call", &[self.rel32()?]),
+            0xe9 => ins("jmp", &[self.rel32()?]),
+            0xeb => ins("jmp", &[self.rel8()?]),
+            0xf4 => ins("hlt", &[]),
+            0xf5 => ins("cmc", &[]),
+            0xf8 => ins("clc", &[]),
+            0xf9 => ins("stc", &[]),
+            0xfa => ins("cli", &[]),
+            0xfb => ins("sti", &[]),
+            0xfc => ins("cld", &[]),
+            0xfd => ins("std", &[]),
+            0xf6 | 0xf7 => {
+                let s = if op == 0xf6 { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                match r & 7 {
+                    0 | 1 => ins("test", &[rm, Operand::Imm(self.imm(s)?)]),
+                    2 => ins("not", &[rm]),
+                    3 => ins("neg", &[rm]),
+                    4 => ins("mul", &[rm]),
+                    5 => ins("imul", &[rm]),
+                    6 => ins("div", &[rm]),
+                    _ => ins("idiv", &[rm]),
+                }
+            }
+            0xfe => match self.modrm(Size::Byte)? {
+                (r, rm) if r & 7 == 0 => ins("inc", &[rm]),
+                (r, rm) if r & 7 == 1 => ins("dec", &[rm]),
+                _ => return None,
+            },
+            0xff => {
+                // the reg field decides the operand size
+                let r = self.peek()? >> 3 & 7;
+                let s = if r < 2 { self.osize() } else { self.stack_size() };
+                let (_, rm) = self.modrm(s)?;
+                match r {
+                    0 => ins("inc", &[rm]),
+                    1 => ins("dec", &[rm]),
+                    2 => ins("call", &[rm]),
+                    4 => ins("jmp", &[rm]),
+                    6 => ins("push", &[rm]),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        if self.lock {
+            d.prefix = "lock ";
+        }
+        Some(d)
+    }
+
+    /// two byte opcodes, 0x0f has been read
+    fn decode_0f(&mut self) -> Option<Decoded> {
+        let op = self.byte()?;
+        Some(match op {
+            0x01 => {
+                let modrm = self.peek()?;
+                let fixed = match modrm {
+                    0xd0 => Some("xgetbv"),
+                    0xf8 => Some("swapgs"),
+                    0xf9 => Some("rdtscp"),
+                    0xca => Some("clac"),
+                    0xcb => Some("stac"),
+                    _ => None,
+                };
+                if let Some(name) = fixed {
+                    self.pos += 1;
+                    return Some(ins(name, &[]));
+                }
+                let (r, m) = self.modrm_address()?;
+                let name = match r & 7 {
+                    0 => "sgdt",
+                    1 => "sidt",
+                    2 => "lgdt",
+                    3 => "lidt",
+                    7 => "invlpg",
+                    _ => return None,
+                };
+                ins(name, &[m])
+            }
+            0x05 => ins("syscall", &[]),
+            0x07 => ins(if self.rex_w() { "sysretq" } else { "sysretd" }, &[]),
+            0x09 => ins("wbinvd", &[]),
+            0x0b => ins("ud2", &[]),
+            0x1f => {
+                let (_, rm) = self.modrm(self.osize())?;
+                ins("nop", &[rm])
+            }
+            0x20 => {
+                let (cr, reg) = self.modrm_regs()?;
+                ins("mov", &[Operand::Reg(reg, Size::Qword), Operand::Cr(cr)])
+            }
+            0x21 => {
+                let (dr, reg) = self.modrm_regs()?;
+                ins("mov", &[Operand::Reg(reg, Size::Qword), Operand::Dr(dr)])
+            }
+            0x22 => {
+                let (cr, reg) = self.modrm_regs()?;
+                ins("mov", &[Operand::Cr(cr), Operand::Reg(reg, Size::Qword)])
+            }
+            0x23 => {
+                let (dr, reg) = self.modrm_regs()?;
+                ins("mov", &[Operand::Dr(dr), Operand::Reg(reg, Size::Qword)])
+            }
+            0x30 => ins("wrmsr", &[]),
+            0x31 => ins("rdtsc", &[]),
+            0x32 => ins("rdmsr", &[]),
+            0x33 => ins("rdpmc", &[]),
+            0x40..=0x4f => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(s)?;
+                ins_suffix("cmov", CONDITIONS[(op & 0xf) as usize], &[Operand::Reg(r, s), rm])
+            }
+            0x80..=0x8f => ins_suffix("j", CONDITIONS[(op & 0xf) as usize], &[self.rel32()?]),
+            0x90..=0x9f => {
+                let (_, rm) = self.modrm(Size::Byte)?;
+                ins_suffix("set", CONDITIONS[(op & 0xf) as usize], &[rm])
+            }
+            0xa2 => ins("cpuid", &[]),
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(s)?;
+                let name = ["bt", "bts", "btr", "btc"][((op >> 3) & 3) as usize];
+                ins(name, &[rm, Operand::Reg(r, s)])
+            }
+            0xaf => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(s)?;
+                ins("imul", &[Operand::Reg(r, s), rm])
+            }
+            0xb0 | 0xb1 | 0xc0 | 0xc1 => {
+                let s = if op & 1 == 0 { Size::Byte } else { self.osize() };
+                let (r, rm) = self.modrm(s)?;
+                ins(if op < 0xc0 { "cmpxchg" } else { "xadd" }, &[rm, Operand::Reg(r, s)])
+            }
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(if op & 1 == 0 { Size::Byte } else { Size::Word })?;
+                ins(if op < 0xbe { "movzx" } else { "movsx" }, &[Operand::Reg(r, s), rm])
+            }
+            0xba => {
+                let s = self.osize();
+                let (r, rm) = self.modrm(s)?;
+                let imm = Operand::Imm(self.byte()? as i64);
+                match r & 7 {
+                    4 => ins("bt", &[rm, imm]),
+                    5 => ins("bts", &[rm, imm]),
+                    6 => ins("btr", &[rm, imm]),
+                    7 => ins("btc", &[rm, imm]),
+                    _ => return None,
+                }
+            }
+            0xc8..=0xcf => ins("bswap", &[Operand::Reg((op & 7) | self.rex_b(), self.osize())]),
+            _ => return None,
+        })
+    }
+}
+
+/// what formatting an operand needs to know about the whole instruction
+struct Context {
+    rex: bool,
+    addr32: bool,
+    segment: Option<&'static str>,
+    next_ip: u64,
+}
+
+fn reg_name(reg: u8, size: Size, rex: bool) -> &'static str {
+    let reg = (reg & 15) as usize;
+    match size {
+        Size::Qword => REGS64[reg],
+        Size::Dword => REGS32[reg],
+        Size::Word => REGS16[reg],
+        Size::Byte if rex => REGS8_REX[reg],
+        Size::Byte => REGS8[reg & 7],
+    }
+}
+
+/// small negative numbers print as such, everything else as unsigned hex
+fn write_imm(out: &mut impl Write, value: i64) -> fmt::Result {
+    if value < 0 && value > -0x10000 {
+        write!(out, "-{:#x}", -value)
+    } else {
+        write!(out, "{:#x}", value as u64)
+    }
+}
+
+fn write_operand(out: &mut impl Write, op: &Operand, ctx: &Context) -> fmt::Result {
+    match *op {
+        Operand::Reg(reg, size) => out.write_str(reg_name(reg, size, ctx.rex)),
+        Operand::Mem { base, index, disp, rip, size } => {
+            if let Some(size) = size {
+                out.write_str(size.ptr())?;
+            }
+            if let Some(segment) = ctx.segment {
+                out.write_str(segment)?;
+            }
+            let addr_size = if ctx.addr32 { Size::Dword } else { Size::Qword };
+            out.write_char('[')?;
+            let mut first = true;
+            if rip {
+                out.write_str("rip")?;
+                first = false;
+            }
+            if let Some(base) = base {
+                out.write_str(reg_name(base, addr_size, true))?;
+                first = false;
+            }
+            if let Some((index, scale)) = index {
+                if !first {
+                    out.write_char('+')?;
+                }
+                write!(out, "{}*{}", reg_name(index, addr_size, true), scale)?;
+                first = false;
+            }
+            if first {
+                // absolute address
+                write!(out, "{:#x}", disp as u64)?;
+            } else if disp < 0 {
+                write!(out, "-{:#x}", -disp)?;
+            } else if disp > 0 {
+                write!(out, "+{:#x}", disp)?;
+            }
+            out.write_char(']')
+        }
+        Operand::Imm(value) => write_imm(out, value),
+        Operand::Rel(target) => write!(out, "{:#x} <{}>", target, Symbol(target)),
+        Operand::Fixed(name) => out.write_str(name),
+        Operand::Cr(n) => write!(out, "cr{}", n),
+        Operand::Dr(n) => write!(out, "dr{}", n),
+    }
+}
+
+impl Decoded {
+    fn write(&self, out: &mut impl Write, ctx: &Context) -> fmt::Result {
+        write!(out, "{}{}{}", self.prefix, self.mnemonic, self.suffix)?;
+        let mut rip_target = None;
+        for (i, op) in self.operands.iter().flatten().enumerate() {
+            out.write_str(if i == 0 { " " } else { ", " })?;
+            write_operand(out, op, ctx)?;
+            if let Operand::Mem { rip: true, disp, .. } = *op {
+                rip_target = Some(ctx.next_ip.wrapping_add(disp as u64));
+            }
+        }
+        if let Some(target) = rip_target {
+            write!(out, "  # {:#x} <{}>", target, Symbol(target))?;
+        }
+        Ok(())
+    }
+}
+
+/// a decoded instruction, ready to print
+pub struct Instruction {
+    pub addr: u64,
+    /// length in bytes, 1 for bytes that didn't decode
+    pub len: usize,
+    text: StrBuf<128>,
+}
+
+impl Instruction {
+    pub fn text(&self) -> &str {
+        self.text.as_str()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.text())
+    }
+}
+
+/// decode the instruction at the start of `bytes`, which was read from `addr`
+pub fn decode(bytes: &[u8], addr: u64) -> Instruction {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        addr,
+        rex: 0,
+        opsize16: false,
+        addr32: false,
+        rep: false,
+        repne: false,
+        lock: false,
+        segment: None,
+    };
+    let mut text = StrBuf::new();
+    match decoder.decode() {
+        Some(decoded) => {
+            let ctx = Context {
+                rex: decoder.rex != 0,
+                addr32: decoder.addr32,
+                segment: decoder.segment,
+                next_ip: decoder.next_ip(),
+            };
+            let _ = decoded.write(&mut text, &ctx);
+            Instruction { addr, len: decoder.pos, text }
+        }
+        None => {
+            let _ = text.write_str("(bad)");
+            Instruction { addr, len: 1, text }
+        }
+    }
+}
+
+/// print `count` instructions starting at `addr`, stops at unmapped memory
+pub fn print(addr: u64, count: usize) {
+    let mut addr = addr;
+    for _ in 0..count {
+        let mut bytes = [0u8; MAX_LEN];
+        let n = match VirtAddr::try_new(addr) {
+            Ok(virt) => memory::read_bytes(virt, &mut bytes),
+            Err(_) => 0,
+        };
+        if n == 0 {
+            println!("{:#x}: not mapped", addr);
+            return;
+        }
+        let insn = decode(&bytes[..n], addr);
+        let mut hex: StrBuf<24> = StrBuf::new();
+        for b in &bytes[..insn.len.min(7)] {
+            let _ = write!(hex, "{:02x}", b);
+        }
+        if insn.len > 7 {
+            let _ = hex.write_str("..");
+        }
+        println!("{:x}: {:<16} {}", addr, hex.as_str(), insn);
+        addr = addr.wrapping_add(insn.len as u64);
+    }
+}
@@ -0,0 +1,474 @@
+//! CPU exceptions
+//! https://wiki.osdev.org/Exceptions
+//!
+//! the `x86-interrupt` handlers only get to see the interrupt stack frame, so
+//! the exceptions go through small assembly stubs instead. they push the
+//! general purpose registers next to what the CPU pushed and hand the lot to
+//! `exception_dispatch` as a `TrapFrame`, which prints everything it knows and
+//! then applies the exception's `Policy`. anything written to the frame is what
+//! `iretq` resumes with.
+//!
+//! the double fault keeps its own handler in `interrupts.rs`, it needs the IST
+//! stack and never returns anyway.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::smp::{self, MAX_CPUS};
+use crate::symbols::Symbol;
+use crate::vga_buffer::{self, WRITER, ERROR_COLOR};
+use crate::{apic, backtrace, debugreg, disas, gdbstub, interrupts, percpu, watchdog, println, println_colored};
+
+/// what to do once an exception has been reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// return to the interrupted code, for traps and anything that already
+    /// points past the instruction that raised it
+    Resume,
+    /// abandon whatever was running on this CPU (a shell command, an interrupt
+    /// handler) and go back to the prompt / idle loop
+    KillTask,
+    /// like `KillTask`, but also reset the shell and drivers, see `soft_restart`
+    Restart,
+    /// the machine state can't be trusted any more
+    Panic,
+}
+
+/// how the error code of an exception is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    None,
+    /// segment selector index, see `print_selector_error`
+    Selector,
+    PageFault,
+    /// always 0 (alignment check) or vendor specific (security exception)
+    Raw,
+}
+
+struct Exception {
+    name: &'static str,
+    mnemonic: &'static str,
+    error_code: ErrorCode,
+    policy: Policy,
+}
+
+const fn exception(name: &'static str, mnemonic: &'static str, error_code: ErrorCode, policy: Policy) -> Exception {
+    Exception { name, mnemonic, error_code, policy }
+}
+
+/// by vector, reserved vectors never get a stub
+const EXCEPTIONS: [Exception; 32] = [
+    exception("DIVIDE ERROR", "#DE", ErrorCode::None, Policy::KillTask),
+    exception("DEBUG", "#DB", ErrorCode::None, Policy::Resume),
+    exception("NON MASKABLE INTERRUPT", "NMI", ErrorCode::None, Policy::Resume),
+    exception("BREAKPOINT", "#BP", ErrorCode::None, Policy::Resume),
+    exception("OVERFLOW", "#OF", ErrorCode::None, Policy::Resume),
+    exception("BOUND RANGE EXCEEDED", "#BR", ErrorCode::None, Policy::KillTask),
+    exception("INVALID OPCODE", "#UD", ErrorCode::None, Policy::KillTask),
+    exception("DEVICE NOT AVAILABLE", "#NM", ErrorCode::None, Policy::KillTask),
+    exception("DOUBLE FAULT", "#DF", ErrorCode::Raw, Policy::Panic),
+    exception("COPROCESSOR SEGMENT OVERRUN", "", ErrorCode::None, Policy::Panic),
+    exception("INVALID TSS", "#TS", ErrorCode::Selector, Policy::Panic),
+    exception("SEGMENT NOT PRESENT", "#NP", ErrorCode::Selector, Policy::Restart),
+    exception("STACK SEGMENT FAULT", "#SS", ErrorCode::Selector, Policy::Panic),
+    exception("GENERAL PROTECTION FAULT", "#GP", ErrorCode::Selector, Policy::KillTask),
+    exception("PAGE FAULT", "#PF", ErrorCode::PageFault, Policy::KillTask),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("X87 FLOATING POINT", "#MF", ErrorCode::None, Policy::KillTask),
+    exception("ALIGNMENT CHECK", "#AC", ErrorCode::Raw, Policy::KillTask),
+    exception("MACHINE CHECK", "#MC", ErrorCode::None, Policy::Panic),
+    exception("SIMD FLOATING POINT", "#XM", ErrorCode::None, Policy::KillTask),
+    exception("VIRTUALIZATION", "#VE", ErrorCode::None, Policy::Panic),
+    exception("CONTROL PROTECTION", "#CP", ErrorCode::Raw, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+    exception("HYPERVISOR INJECTION", "#HV", ErrorCode::None, Policy::Panic),
+    exception("VMM COMMUNICATION", "#VC", ErrorCode::Raw, Policy::Panic),
+    exception("SECURITY EXCEPTION", "#SX", ErrorCode::Raw, Policy::Panic),
+    exception("RESERVED", "", ErrorCode::None, Policy::Panic),
+];
+
+/// everything the stubs leave on the stack, lowest address first
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for exceptions without one
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// every stub pushes a fake error code if the CPU doesn't, so all of them
+// share one frame layout. the 15 registers plus the 7 words below them keep
+// the stack 16 byte aligned for the call, since the CPU aligns it before
+// pushing its frame.
+global_asm!(r#"
+.macro exception_stub vector, has_error_code
+.global exception_stub_\vector
+exception_stub_\vector:
+.if \has_error_code == 0
+    pushq $0
+.endif
+    pushq $\vector
+    jmp exception_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 30, 1
+
+exception_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, %rdi
+    cld
+    callq exception_dispatch
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    addq $16, %rsp
+    iretq
+"#, options(att_syntax));
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_1();
+    fn exception_stub_2();
+    fn exception_stub_3();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_30();
+}
+
+fn stub(f: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(f as *const () as u64)
+}
+
+/// point the exception entries of `idt` at the stubs
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(exception_stub_0));
+        idt.debug.set_handler_addr(stub(exception_stub_1));
+        idt.non_maskable_interrupt.set_handler_addr(stub(exception_stub_2));
+        idt.breakpoint.set_handler_addr(stub(exception_stub_3));
+        idt.overflow.set_handler_addr(stub(exception_stub_4));
+        idt.bound_range_exceeded.set_handler_addr(stub(exception_stub_5));
+        idt.invalid_opcode.set_handler_addr(stub(exception_stub_6));
+        idt.device_not_available.set_handler_addr(stub(exception_stub_7));
+        idt.invalid_tss.set_handler_addr(stub(exception_stub_10));
+        idt.segment_not_present.set_handler_addr(stub(exception_stub_11));
+        idt.stack_segment_fault.set_handler_addr(stub(exception_stub_12));
+        idt.general_protection_fault.set_handler_addr(stub(exception_stub_13));
+        idt.page_fault.set_handler_addr(stub(exception_stub_14));
+        idt.x87_floating_point.set_handler_addr(stub(exception_stub_16));
+        idt.alignment_check.set_handler_addr(stub(exception_stub_17));
+        idt.machine_check.set_handler_addr(stub(exception_stub_18));
+        idt.simd_floating_point.set_handler_addr(stub(exception_stub_19));
+        idt.virtualization.set_handler_addr(stub(exception_stub_20));
+        idt.security_exception.set_handler_addr(stub(exception_stub_30));
+    }
+}
+
+const RECOVERY_STACK_SIZE: usize = 4096 * 4;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Stack([u8; RECOVERY_STACK_SIZE]);
+
+/// `task_killed` and `soft_restart` run on these, the stack of whatever was
+/// abandoned may be what broke
+static mut RECOVERY_STACKS: [Stack; MAX_CPUS] = [Stack([0; RECOVERY_STACK_SIZE]); MAX_CPUS];
+
+/// exceptions currently being handled per CPU, more than one means the
+/// handler itself faulted
+#[allow(clippy::declare_interior_mutable_const)]
+const DEPTH_INIT: AtomicUsize = AtomicUsize::new(0);
+static DEPTH: [AtomicUsize; MAX_CPUS] = [DEPTH_INIT; MAX_CPUS];
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// for exceptions that return: the console locks are left alone, another CPU
+/// may be in the middle of a line. false if they stay held, most likely by
+/// the code this CPU was interrupted in
+fn wait_for_console() -> bool {
+    for _ in 0..1_000_000 {
+        if WRITER.try_lock().is_some() && !crate::serial::console_is_locked() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// the console lock (or a serial port, `print!` writes to one too) may be
+/// held by the code that faulted, on this CPU that would never let go. give
+/// other CPUs a moment to finish their line first
+pub fn unjam_console() {
+    crate::serial::unjam();
+    for _ in 0..1_000_000 {
+        if WRITER.try_lock().is_some() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { WRITER.force_unlock() };
+}
+
+fn print_selector_error(code: u64) {
+    if code == 0 {
+        println!("error code 0 (not selector related)");
+        return;
+    }
+    let table = match (code >> 1) & 0b11 {
+        0b00 => "GDT",
+        0b10 => "LDT",
+        _ => "IDT",
+    };
+    println!("error code {:#x}: {} index {} (selector {:#x}){}", code, table, (code >> 3) & 0x1fff,
+        code & 0xfff8, if code & 1 != 0 { ", external event" } else { "" });
+}
+
+fn print_registers(frame: &TrapFrame) {
+    println!("RIP {:#018x} {}", frame.rip, Symbol(frame.rip));
+    println!("CS {:#06x} RFLAGS {:#010x}", frame.cs, frame.rflags);
+    println!("RSP {:#018x} SS {:#06x}", frame.rsp, frame.ss);
+    println!("RAX {:016x} RBX {:016x} RCX {:016x}", frame.rax, frame.rbx, frame.rcx);
+    println!("RDX {:016x} RSI {:016x} RDI {:016x}", frame.rdx, frame.rsi, frame.rdi);
+    println!("RBP {:016x} R8  {:016x} R9  {:016x}", frame.rbp, frame.r8, frame.r9);
+    println!("R10 {:016x} R11 {:016x} R12 {:016x}", frame.r10, frame.r11, frame.r12);
+    println!("R13 {:016x} R14 {:016x} R15 {:016x}", frame.r13, frame.r14, frame.r15);
+    let (cr3, _) = Cr3::read();
+    println!("CR0 {:016x} CR2 {:016x}", Cr0::read_raw(), Cr2::read().as_u64());
+    println!("CR3 {:016x} CR4 {:016x}", cr3.start_address().as_u64(), Cr4::read_raw());
+}
+
+fn report(frame: &TrapFrame, info: &Exception) {
+    let _color = vga_buffer::set_foreground(ERROR_COLOR);
+    println!("EXCEPTION: {} ({} vector {}) on CPU {}", info.name, info.mnemonic, frame.vector,
+        percpu::cpu_id());
+    match info.error_code {
+        ErrorCode::None => {}
+        ErrorCode::Selector => print_selector_error(frame.error_code),
+        ErrorCode::PageFault => {
+            println!("accessed address {:#x}", Cr2::read().as_u64());
+            println!("error code {:?}", PageFaultErrorCode::from_bits_truncate(frame.error_code));
+        }
+        ErrorCode::Raw => println!("error code {:#x}", frame.error_code),
+    }
+    print_registers(frame);
+    disas::print(frame.rip, 3);
+    backtrace::print_from(frame.rip, frame.rbp);
+}
+
+/// whatever was running when a task got killed is gone, so nobody will send
+/// its EOIs or drop its locks. runs on the recovery stack with interrupts off
+extern "C" fn task_killed() -> ! {
+    let cpu = percpu::current();
+    if cpu.irq_depth() > 0 {
+        if cpu.cpu_id() == 0 {
+            interrupts::abandon_pic_interrupts();
+        }
+        if apic::is_initialized() {
+            apic::eoi();
+        }
+    }
+    cpu.reset_irq_depth();
+    reset();
+    crate::lock::reset_held();
+
+    if cpu.cpu_id() == 0 {
+        // a color the task set is still in effect
+        WRITER.lock().reset_attributes();
+        crate::cmd::print_prompt();
+    }
+    x86_64::instructions::interrupts::enable();
+    smp::idle_loop();
+}
+
+extern "C" fn restart() -> ! {
+    crate::soft_restart();
+}
+
+fn recovery_stack_top() -> u64 {
+    let cpu = percpu::cpu_id();
+    unsafe { core::ptr::addr_of!(RECOVERY_STACKS[cpu]) as u64 + RECOVERY_STACK_SIZE as u64 }
+}
+
+/// make `iretq` continue in `entry` on this CPU's recovery stack
+fn resume_on_recovery_stack(frame: &mut TrapFrame, entry: extern "C" fn() -> !) {
+    frame.rip = entry as *const () as u64;
+    // as if `entry` had been called, and the end of the backtrace
+    frame.rsp = recovery_stack_top() - 8;
+    frame.rbp = 0;
+    frame.rflags &= !(RFLAGS_IF | RFLAGS_TF);
+}
+
+/// abandon the current stack and call `entry` on this CPU's recovery stack,
+/// with interrupts disabled
+pub fn run_on_recovery_stack(entry: extern "C" fn() -> !) -> ! {
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {entry}",
+            top = in(reg) recovery_stack_top(),
+            entry = in(reg) entry,
+            options(noreturn),
+        );
+    }
+}
+
+/// forget about the exceptions this CPU was handling, for when their
+/// handlers were abandoned
+pub fn reset() {
+    DEPTH[percpu::cpu_id()].store(0, Ordering::SeqCst);
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut TrapFrame) {
+    // the watchdog's NMIs come all the time, also while other exceptions
+    // are handled, so they stay out of the bookkeeping below
+    if frame.vector == 2 && watchdog::handle_nmi(frame) {
+        return;
+    }
+
+    let info = &EXCEPTIONS[frame.vector as usize & 31];
+    let cpu = percpu::cpu_id();
+    percpu::current().stats.exceptions.fetch_add(1, Ordering::Relaxed);
+    let depth = DEPTH[cpu].fetch_add(1, Ordering::SeqCst);
+
+    if frame.vector == 1 && depth > 0 {
+        // a watchpoint on something the handler itself touches
+        debugreg::acknowledge(frame);
+        DEPTH[cpu].fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    if gdbstub::is_active() && depth == 0 {
+        gdbstub::handle(frame);
+        // gdb has seen the faults, they still get handled as usual
+        if matches!(frame.vector, 1 | 3) {
+            DEPTH[cpu].fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+    }
+
+    let policy = if depth > 0 { Policy::Panic } else { info.policy };
+    // breaking the console locks is only safe when what held them won't
+    // carry on, a resumed #BP or #DB may well be in the middle of printing
+    let emergency = if policy == Policy::Resume {
+        !wait_for_console()
+    } else {
+        unjam_console();
+        false
+    };
+    vga_buffer::set_emergency(emergency);
+    match frame.vector {
+        1 => debugreg::handle_debug(frame),
+        3 => {
+            report(frame, info);
+            debugreg::continue_stepping(frame);
+        }
+        _ => report(frame, info),
+    }
+    vga_buffer::set_emergency(false);
+
+    match policy {
+        Policy::Resume => {}
+        Policy::KillTask => {
+            println_colored!(ERROR_COLOR, "killing the current task");
+            resume_on_recovery_stack(frame, task_killed);
+        }
+        Policy::Restart => {
+            println_colored!(ERROR_COLOR, "restarting the shell");
+            resume_on_recovery_stack(frame, restart);
+        }
+        Policy::Panic => {
+            if depth > 0 {
+                panic!("{} while handling an exception", info.name);
+            }
+            panic!("EXCEPTION: {}", info.name);
+        }
+    }
+    DEPTH[cpu].fetch_sub(1, Ordering::SeqCst);
+}
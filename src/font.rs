@@ -0,0 +1,78 @@
+//! PC Screen Font (PSF) bitmap fonts, for text on a framebuffer
+//! https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
+//!
+//! the built in font is 8x16 with its 256 glyphs in code page 437 order, the
+//! same as the VGA text mode font, so a console's bytes index it directly.
+//! the unicode tables some PSF files carry are ignored for that reason.
+
+use lazy_static::lazy_static;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_LEN: usize = 4;
+/// PSF1 mode bit: 512 glyphs instead of 256
+const PSF1_MODE512: u8 = 0x01;
+
+const PSF2_MAGIC: u32 = 0x864a_b572;
+
+lazy_static! {
+    pub static ref DEFAULT: Font = Font::parse(include_bytes!("font8x16.psf"))
+        .expect("built in font is not a PSF font");
+}
+
+pub struct Font {
+    pub width: usize,
+    pub height: usize,
+    glyphs: &'static [u8],
+    count: usize,
+    bytes_per_glyph: usize,
+    /// a glyph row padded to whole bytes
+    bytes_per_row: usize,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl Font {
+    /// a PSF1 or PSF2 font, None if it's neither or cut short
+    pub fn parse(data: &'static [u8]) -> Option<Font> {
+        let (width, height, count, bytes_per_glyph, header_len) = if data.get(..2)? == PSF1_MAGIC {
+            let mode = data[2];
+            let height = data[3] as usize;
+            let count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+            (8, height, count, height, PSF1_HEADER_LEN)
+        } else if read_u32(data, 0)? == PSF2_MAGIC {
+            let header_len = read_u32(data, 8)? as usize;
+            let count = read_u32(data, 16)? as usize;
+            let bytes_per_glyph = read_u32(data, 20)? as usize;
+            let height = read_u32(data, 24)? as usize;
+            let width = read_u32(data, 28)? as usize;
+            (width, height, count, bytes_per_glyph, header_len)
+        } else {
+            return None;
+        };
+
+        let bytes_per_row = (width + 7) / 8;
+        if width == 0 || height == 0 || bytes_per_glyph < bytes_per_row * height {
+            return None;
+        }
+        let glyphs = data.get(header_len..header_len + count * bytes_per_glyph)?;
+        Some(Font { width, height, glyphs, count, bytes_per_glyph, bytes_per_row })
+    }
+
+    /// the bitmap of glyph `index`, rows top to bottom, each padded to whole
+    /// bytes with the leftmost pixel in the top bit. glyphs the font doesn't
+    /// have show as its glyph 0
+    pub fn glyph(&self, index: usize) -> &'static [u8] {
+        let index = if index < self.count { index } else { 0 };
+        let start = index * self.bytes_per_glyph;
+        &self.glyphs[start..start + self.bytes_per_glyph]
+    }
+
+    /// whether pixel (x, y) of a glyph from `glyph` is set
+    pub fn pixel(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let row = y * self.bytes_per_row;
+        glyph[row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
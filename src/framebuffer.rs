@@ -0,0 +1,265 @@
+//! linear framebuffer through the Bochs VBE extensions ("dispi" interface)
+//! https://wiki.osdev.org/Bochs_VBE_Extensions
+//!
+//! QEMU's `-vga std` and Bochs have it: the resolution and color depth are
+//! set through an index/data port pair, no BIOS call needed. the framebuffer
+//! is BAR0 of the VGA PCI device, all pixels are 32 bit 0x00RRGGBB.
+//!
+//! with double buffering the virtual screen is two screens high: drawing
+//! goes to the half that isn't shown, `present` flips the display start over
+//! to it during vertical retrace.
+//!
+//! the VGA text mode font and characters live in the same video memory, so
+//! going back to text mode after `disable` is a job for `text_mode::set`.
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::memory;
+
+const DISPI_INDEX: u16 = 0x1ce;
+const DISPI_DATA: u16 = 0x1cf;
+
+const DISPI_ID: u16 = 0;
+const DISPI_XRES: u16 = 1;
+const DISPI_YRES: u16 = 2;
+const DISPI_BPP: u16 = 3;
+const DISPI_ENABLE: u16 = 4;
+const DISPI_VIRT_WIDTH: u16 = 6;
+const DISPI_VIRT_HEIGHT: u16 = 7;
+const DISPI_Y_OFFSET: u16 = 9;
+
+/// `DISPI_ID` reads 0xb0c0 to 0xb0c5 depending on the version
+const DISPI_ID_MIN: u16 = 0xb0c0;
+const DISPI_ID_MAX: u16 = 0xb0c5;
+
+// in `DISPI_ENABLE`
+const DISPI_ENABLED: u16 = 0x01;
+/// while set, XRES/YRES/BPP read back the largest values supported
+const DISPI_GETCAPS: u16 = 0x02;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+const BPP: u16 = 32;
+
+/// VGA input status #1, bit 3 is set during vertical retrace
+const INPUT_STATUS: u16 = 0x3da;
+const VERTICAL_RETRACE: u8 = 1 << 3;
+/// polls before giving up on seeing a retrace
+const RETRACE_TIMEOUT: usize = 1_000_000;
+
+// PCI configuration space through the legacy ports
+// https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+const PCI_ENABLE: u32 = 1 << 31;
+const PCI_BAR0: u8 = 0x10;
+
+/// the QEMU/Bochs VGA device
+const BOCHS_VENDOR: u16 = 0x1234;
+const BOCHS_DEVICE: u16 = 0x1111;
+/// where Bochs puts the framebuffer when it's not a PCI device
+const DEFAULT_LFB: u64 = 0xe000_0000;
+
+fn read_dispi(reg: u16) -> u16 {
+    unsafe {
+        Port::<u16>::new(DISPI_INDEX).write(reg);
+        Port::<u16>::new(DISPI_DATA).read()
+    }
+}
+
+fn write_dispi(reg: u16, value: u16) {
+    unsafe {
+        Port::<u16>::new(DISPI_INDEX).write(reg);
+        Port::<u16>::new(DISPI_DATA).write(value);
+    }
+}
+
+fn read_pci(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = PCI_ENABLE
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset & 0xfc) as u32;
+    unsafe {
+        Port::<u32>::new(PCI_CONFIG_ADDRESS).write(address);
+        Port::<u32>::new(PCI_CONFIG_DATA).read()
+    }
+}
+
+/// physical address of the framebuffer, from BAR0 of the VGA device on bus 0
+fn lfb_address() -> u64 {
+    for device in 0..32 {
+        let id = read_pci(0, device, 0, 0);
+        if id as u16 == BOCHS_VENDOR && (id >> 16) as u16 == BOCHS_DEVICE {
+            // a 32 bit memory BAR, the low bits are flags
+            return (read_pci(0, device, 0, PCI_BAR0) & !0xf) as u64;
+        }
+    }
+    DEFAULT_LFB
+}
+
+/// whether the Bochs VBE registers are there at all
+pub fn is_available() -> bool {
+    (DISPI_ID_MIN..=DISPI_ID_MAX).contains(&read_dispi(DISPI_ID))
+}
+
+/// the largest resolution the adapter can do
+pub fn max_resolution() -> (usize, usize) {
+    let enable = read_dispi(DISPI_ENABLE);
+    write_dispi(DISPI_ENABLE, enable | DISPI_GETCAPS);
+    let max = (read_dispi(DISPI_XRES) as usize, read_dispi(DISPI_YRES) as usize);
+    write_dispi(DISPI_ENABLE, enable);
+    max
+}
+
+/// waits for the start of the next vertical retrace, so whatever changes the
+/// display then doesn't tear
+fn wait_for_retrace() {
+    let mut status = Port::<u8>::new(INPUT_STATUS);
+    for _ in 0..RETRACE_TIMEOUT {
+        if unsafe { status.read() } & VERTICAL_RETRACE == 0 {
+            break;
+        }
+    }
+    for _ in 0..RETRACE_TIMEOUT {
+        if unsafe { status.read() } & VERTICAL_RETRACE != 0 {
+            break;
+        }
+    }
+}
+
+/// A graphics mode's video memory, one u32 per pixel.
+pub struct Framebuffer {
+    phys: PhysAddr,
+    base: *mut u32,
+    pub width: usize,
+    pub height: usize,
+    /// pixels from the start of one row to the next
+    stride: usize,
+    double_buffered: bool,
+    /// the screen drawing goes to, 1 is the lower half of the virtual screen
+    back: usize,
+}
+
+// the framebuffer is mapped for every CPU, who draws is up to whoever owns it
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// where pixel (x, y) of the screen being drawn on is
+    fn address(&self, x: usize, y: usize) -> *mut u32 {
+        unsafe { self.base.add((self.back * self.height + y) * self.stride + x) }
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            unsafe { self.address(x, y).write_volatile(color) };
+        }
+    }
+
+    /// The pixel at (x, y), black outside the screen.
+    pub fn get_pixel(&self, x: usize, y: usize) -> u32 {
+        if x < self.width && y < self.height {
+            unsafe { self.address(x, y).read_volatile() }
+        } else {
+            0
+        }
+    }
+
+    /// Fills a rectangle, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        for y in y..(y + height).min(self.height) {
+            for x in x..(x + width).min(self.width) {
+                unsafe { self.address(x, y).write_volatile(color) };
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Draws on a hidden second screen from now on, shown by `present`.
+    /// False if there isn't video memory for two screens.
+    pub fn enable_double_buffering(&mut self) -> bool {
+        if self.double_buffered {
+            return true;
+        }
+        write_dispi(DISPI_VIRT_HEIGHT, (self.height * 2) as u16);
+        if (read_dispi(DISPI_VIRT_HEIGHT) as usize) < self.height * 2 {
+            write_dispi(DISPI_VIRT_HEIGHT, self.height as u16);
+            return false;
+        }
+        let size = (self.stride * self.height * 2 * core::mem::size_of::<u32>()) as u64;
+        memory::map_mmio(self.phys, size);
+        write_dispi(DISPI_Y_OFFSET, 0);
+        self.double_buffered = true;
+        self.back = 1;
+        true
+    }
+
+    /// Back to drawing straight on the screen, which shows the top half again.
+    pub fn disable_double_buffering(&mut self) {
+        if !self.double_buffered {
+            return;
+        }
+        wait_for_retrace();
+        write_dispi(DISPI_Y_OFFSET, 0);
+        write_dispi(DISPI_VIRT_HEIGHT, self.height as u16);
+        self.double_buffered = false;
+        self.back = 0;
+    }
+
+    /// Shows what was drawn since the last call, when double buffered. The
+    /// next frame is drawn over the one shown before this one.
+    pub fn present(&mut self) {
+        if !self.double_buffered {
+            return;
+        }
+        wait_for_retrace();
+        write_dispi(DISPI_Y_OFFSET, (self.back * self.height) as u16);
+        self.back ^= 1;
+    }
+}
+
+/// Turns the graphics mode off, the VGA registers are in charge again.
+pub fn disable() {
+    if is_available() {
+        write_dispi(DISPI_ENABLE, 0);
+    }
+}
+
+/// Switches to a `width`x`height` 32 bit graphics mode and maps its
+/// framebuffer. None if there is no Bochs VBE or it can't do that resolution.
+pub fn set_mode(width: usize, height: usize) -> Option<Framebuffer> {
+    if !is_available() || width == 0 || height == 0 {
+        return None;
+    }
+    let (max_width, max_height) = max_resolution();
+    if width > max_width || height > max_height {
+        return None;
+    }
+
+    write_dispi(DISPI_ENABLE, 0);
+    write_dispi(DISPI_XRES, width as u16);
+    write_dispi(DISPI_YRES, height as u16);
+    write_dispi(DISPI_BPP, BPP);
+    write_dispi(DISPI_VIRT_WIDTH, width as u16);
+    write_dispi(DISPI_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+    if read_dispi(DISPI_XRES) as usize != width || read_dispi(DISPI_YRES) as usize != height {
+        return None;
+    }
+
+    // the virtual width may have been rounded up
+    let stride = (read_dispi(DISPI_VIRT_WIDTH) as usize).max(width);
+    let size = (stride * height * core::mem::size_of::<u32>()) as u64;
+    let phys = PhysAddr::new(lfb_address());
+    let base = memory::map_mmio(phys, size);
+    Some(Framebuffer {
+        phys,
+        base: base.as_mut_ptr(),
+        width,
+        height,
+        stride,
+        double_buffered: false,
+        back: 0,
+    })
+}
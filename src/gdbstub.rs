@@ -0,0 +1,526 @@
+//! GDB remote serial protocol stub on COM1
+//! https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//!
+//! `gdb` in the shell starts it and stops in a breakpoint, then
+//!
+//!     (gdb) target remote /dev/ttyS0        # or QEMU's -serial tcp::1234,server
+//!
+//! while it's active every exception on any CPU stops in the stub before it's
+//! handled as usual. the stopped CPU polls the serial port with interrupts
+//! off, the other CPUs keep running. software breakpoints are `int3` bytes
+//! written over the code (with CR0.WP off, kernel text is read-only), single
+//! stepping sets TF and waits for the #DB.
+//!
+//! there's no receive interrupt, so gdb can't interrupt (Ctrl-C) a running
+//! kernel, only breakpoints stop it.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+use crate::exceptions::TrapFrame;
+use crate::lock::Mutex;
+use crate::serial::{SerialPort, COM1};
+use crate::strutils::StrBuf;
+use crate::{debugreg, memory, watchdog, println};
+
+/// largest packet we take or send, told to gdb in `qSupported`
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+
+const RFLAGS_TF: u64 = 1 << 8;
+
+// signals gdb knows the stop reasons by
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// the amd64 register numbers in a `g` packet, the rest (x87, SSE) we don't have
+const REGISTERS: usize = 24;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// the byte the `int3` replaced
+    saved: u8,
+}
+
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+struct Stub {
+    breakpoints: Breakpoints,
+    packet: [u8; PACKET_SIZE],
+    reply: StrBuf<PACKET_SIZE>,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// also keeps a second CPU out while one is stopped
+static STUB: Mutex<Stub> = Mutex::new("gdb stub", Stub {
+    breakpoints: Breakpoints([None; MAX_BREAKPOINTS]),
+    packet: [0; PACKET_SIZE],
+    reply: StrBuf::new(),
+});
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// set up COM1 and stop in the stub until gdb continues
+pub fn start() {
+    if is_active() {
+        println!("gdb: already active");
+        return;
+    }
+    COM1.lock().init();
+    ACTIVE.store(true, Ordering::SeqCst);
+    println!("gdb: waiting on COM1, 115200 8N1");
+    x86_64::instructions::interrupts::int3();
+}
+
+fn signal(vector: u64) -> u8 {
+    match vector {
+        0 | 16 | 19 => SIGFPE,
+        1 | 3 => SIGTRAP,
+        6 => SIGILL,
+        13 | 14 => SIGSEGV,
+        _ => SIGBUS,
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// a big endian hex number as gdb writes addresses and lengths
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0u64, |n, &c| Some(n << 4 | hex_digit(c)? as u64))
+}
+
+/// `size` bytes of little endian hex, as in register packets
+fn parse_le(s: &[u8], size: usize) -> Option<u64> {
+    if s.len() < size * 2 {
+        return None;
+    }
+    let mut value = 0;
+    for i in 0..size {
+        let byte = hex_digit(s[2 * i])? << 4 | hex_digit(s[2 * i + 1])?;
+        value |= (byte as u64) << (8 * i);
+    }
+    Some(value)
+}
+
+fn write_le(reply: &mut StrBuf<PACKET_SIZE>, value: u64, size: usize) {
+    for i in 0..size {
+        let _ = write!(reply, "{:02x}", (value >> (8 * i)) as u8);
+    }
+}
+
+/// "addr,len" as in `m`, `M` and `Z` packets
+fn parse_addr_len(s: &[u8]) -> Option<(u64, u64)> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    Some((parse_hex(&s[..comma])?, parse_hex(&s[comma + 1..])?))
+}
+
+fn segment(n: usize) -> u64 {
+    let value: u16;
+    unsafe {
+        match n {
+            20 => asm!("mov {:x}, ds", out(reg) value, options(nomem, nostack, preserves_flags)),
+            21 => asm!("mov {:x}, es", out(reg) value, options(nomem, nostack, preserves_flags)),
+            22 => asm!("mov {:x}, fs", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov {:x}, gs", out(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    value as u64
+}
+
+/// register `n` in gdb's amd64 numbering and its size in bytes
+fn register(frame: &TrapFrame, n: usize) -> (u64, usize) {
+    let f = frame;
+    match n {
+        0 => (f.rax, 8), 1 => (f.rbx, 8), 2 => (f.rcx, 8), 3 => (f.rdx, 8),
+        4 => (f.rsi, 8), 5 => (f.rdi, 8), 6 => (f.rbp, 8), 7 => (f.rsp, 8),
+        8 => (f.r8, 8), 9 => (f.r9, 8), 10 => (f.r10, 8), 11 => (f.r11, 8),
+        12 => (f.r12, 8), 13 => (f.r13, 8), 14 => (f.r14, 8), 15 => (f.r15, 8),
+        16 => (f.rip, 8),
+        17 => (f.rflags, 4),
+        18 => (f.cs, 4),
+        19 => (f.ss, 4),
+        _ => (segment(n), 4),
+    }
+}
+
+/// segment registers can't be changed from gdb, writes to them are dropped
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) {
+    let f = frame;
+    match n {
+        0 => f.rax = value, 1 => f.rbx = value, 2 => f.rcx = value, 3 => f.rdx = value,
+        4 => f.rsi = value, 5 => f.rdi = value, 6 => f.rbp = value, 7 => f.rsp = value,
+        8 => f.r8 = value, 9 => f.r9 = value, 10 => f.r10 = value, 11 => f.r11 = value,
+        12 => f.r12 = value, 13 => f.r13 = value, 14 => f.r14 = value, 15 => f.r15 = value,
+        16 => f.rip = value,
+        17 => f.rflags = value,
+        _ => {}
+    }
+}
+
+/// write to mapped memory even if it's read-only, for breakpoints in the
+/// kernel text
+fn write_memory(addr: u64, data: &[u8]) -> bool {
+    let start = match VirtAddr::try_new(addr) {
+        Ok(start) => start,
+        Err(_) => return false,
+    };
+    let len = data.len() as u64;
+    if !memory::range_accessible(start, len, false) {
+        return false;
+    }
+    let read_only = !memory::range_accessible(start, len, true);
+    let cr0 = Cr0::read();
+    if read_only {
+        unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+    }
+    for (i, &byte) in data.iter().enumerate() {
+        unsafe { ((addr + i as u64) as *mut u8).write_volatile(byte) };
+    }
+    if read_only {
+        unsafe { Cr0::write(cr0) };
+    }
+    true
+}
+
+fn read_byte(addr: u64) -> Option<u8> {
+    let mut byte = [0u8];
+    let start = VirtAddr::try_new(addr).ok()?;
+    if memory::read_bytes(start, &mut byte) == 1 { Some(byte[0]) } else { None }
+}
+
+impl Breakpoints {
+    fn contains(&self, addr: u64) -> bool {
+        self.0.iter().flatten().any(|b| b.addr == addr)
+    }
+
+    fn insert(&mut self, addr: u64) -> bool {
+        if self.contains(addr) {
+            return true;
+        }
+        let slot = match self.0.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let saved = match read_byte(addr) {
+            Some(saved) => saved,
+            None => return false,
+        };
+        if !write_memory(addr, &[INT3]) {
+            return false;
+        }
+        self.0[slot] = Some(Breakpoint { addr, saved });
+        true
+    }
+
+    fn remove(&mut self, addr: u64) -> bool {
+        for slot in self.0.iter_mut() {
+            if let Some(b) = *slot {
+                if b.addr == addr {
+                    write_memory(b.addr, &[b.saved]);
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn remove_all(&mut self) {
+        for slot in self.0.iter_mut() {
+            if let Some(b) = slot.take() {
+                write_memory(b.addr, &[b.saved]);
+            }
+        }
+    }
+}
+
+/// wait for gdb. the watchdog would take the stopped CPU for a stuck one
+fn receive_byte(port: &mut SerialPort) -> u8 {
+    loop {
+        if let Some(byte) = port.try_read_byte() {
+            return byte;
+        }
+        watchdog::pet();
+        core::hint::spin_loop();
+    }
+}
+
+/// wait for the next packet with a good checksum and ack it, returns its length
+fn receive(port: &mut SerialPort, packet: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while receive_byte(port) != b'$' {}
+
+        let mut len = 0;
+        let mut sum: u8 = 0;
+        let mut complete = true;
+        loop {
+            let c = receive_byte(port);
+            match c {
+                b'#' => break,
+                // gdb gave up on that one and started over
+                b'$' => {
+                    len = 0;
+                    sum = 0;
+                    complete = true;
+                }
+                _ if len == PACKET_SIZE => complete = false,
+                _ => {
+                    packet[len] = c;
+                    len += 1;
+                    sum = sum.wrapping_add(c);
+                }
+            }
+        }
+        let high = hex_digit(receive_byte(port));
+        let low = hex_digit(receive_byte(port));
+        match (high, low) {
+            (Some(h), Some(l)) if complete && h << 4 | l == sum => {
+                port.write_byte(b'+');
+                return len;
+            }
+            _ => port.write_byte(b'-'),
+        }
+    }
+}
+
+/// send `data` as a packet until gdb acks it
+fn send(port: &mut SerialPort, data: &str) {
+    let sum = data.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+    for _ in 0..10 {
+        port.write_byte(b'$');
+        for c in data.bytes() {
+            port.write_byte(c);
+        }
+        port.write_byte(b'#');
+        for digit in [sum >> 4, sum & 0xf] {
+            port.write_byte(b"0123456789abcdef"[digit as usize]);
+        }
+        // anything but an ack, including a ^C, gets the packet again
+        if receive_byte(port) == b'+' {
+            return;
+        }
+    }
+}
+
+fn reply_ok(reply: &mut StrBuf<PACKET_SIZE>, ok: bool) {
+    // E0e is EFAULT
+    let _ = reply.write_str(if ok { "OK" } else { "E0e" });
+}
+
+fn read_registers(reply: &mut StrBuf<PACKET_SIZE>, frame: &TrapFrame) {
+    for n in 0..REGISTERS {
+        let (value, size) = register(frame, n);
+        write_le(reply, value, size);
+    }
+}
+
+/// registers missing at the end of `hex` are left as they are
+fn write_registers(frame: &mut TrapFrame, hex: &[u8]) {
+    let mut pos = 0;
+    for n in 0..REGISTERS {
+        let (_, size) = register(frame, n);
+        match hex.get(pos..).and_then(|rest| parse_le(rest, size)) {
+            Some(value) => set_register(frame, n, value),
+            None => break,
+        }
+        pos += size * 2;
+    }
+}
+
+/// hex of as many of the `len` bytes at `addr` as are mapped, false if none are
+fn read_memory(reply: &mut StrBuf<PACKET_SIZE>, addr: u64, len: u64) -> bool {
+    let mut buf = [0u8; 256];
+    let len = len.min((PACKET_SIZE as u64 - 4) / 2);
+    let mut done = 0;
+    while done < len {
+        let start = match VirtAddr::try_new(addr.wrapping_add(done)) {
+            Ok(start) => start,
+            Err(_) => break,
+        };
+        let n = (len - done).min(buf.len() as u64) as usize;
+        let read = memory::read_bytes(start, &mut buf[..n]);
+        for &b in &buf[..read] {
+            let _ = write!(reply, "{:02x}", b);
+        }
+        done += read as u64;
+        if read < n {
+            break;
+        }
+    }
+    done > 0 || len == 0
+}
+
+/// "addr,len:hex" of an `M` packet, written in chunks
+fn write_memory_packet(args: &[u8]) -> bool {
+    let colon = match args.iter().position(|&c| c == b':') {
+        Some(colon) => colon,
+        None => return false,
+    };
+    let (addr, len) = match parse_addr_len(&args[..colon]) {
+        Some(v) => v,
+        None => return false,
+    };
+    let hex = &args[colon + 1..];
+    // addr and len are whatever the packet says, a bad one mustn't overflow
+    if len.checked_mul(2) != Some(hex.len() as u64) {
+        return false;
+    }
+    let mut buf = [0u8; 256];
+    for (i, chunk) in hex.chunks(buf.len() * 2).enumerate() {
+        let n = chunk.len() / 2;
+        for (j, byte) in buf[..n].iter_mut().enumerate() {
+            match parse_le(&chunk[2 * j..], 1) {
+                Some(b) => *byte = b as u8,
+                None => return false,
+            }
+        }
+        let chunk_addr = match addr.checked_add((i * buf.len()) as u64) {
+            Some(chunk_addr) => chunk_addr,
+            None => return false,
+        };
+        if !write_memory(chunk_addr, &buf[..n]) {
+            return false;
+        }
+    }
+    true
+}
+
+impl Stub {
+    /// talk to gdb until it resumes execution, returns whether the stub
+    /// should stay active
+    fn run(&mut self, frame: &mut TrapFrame, stop_reply: &str) -> bool {
+        let mut port = COM1.lock();
+        send(&mut port, stop_reply);
+
+        loop {
+            let len = receive(&mut port, &mut self.packet);
+            let reply = &mut self.reply;
+            reply.clear();
+            if len == 0 {
+                send(&mut port, "");
+                continue;
+            }
+            let command = self.packet[0];
+            let args = &self.packet[1..len];
+            match command {
+                b'?' => {
+                    let _ = reply.write_str(stop_reply);
+                }
+                b'g' => read_registers(reply, frame),
+                b'G' => {
+                    write_registers(frame, args);
+                    reply_ok(reply, true);
+                }
+                b'p' => match parse_hex(args) {
+                    Some(n) if (n as usize) < REGISTERS => {
+                        let (value, size) = register(frame, n as usize);
+                        write_le(reply, value, size);
+                    }
+                    _ => reply_ok(reply, false),
+                },
+                b'P' => {
+                    let ok = args.iter().position(|&c| c == b'=').and_then(|eq| {
+                        let n = parse_hex(&args[..eq])? as usize;
+                        if n >= REGISTERS {
+                            return None;
+                        }
+                        let (_, size) = register(frame, n);
+                        set_register(frame, n, parse_le(&args[eq + 1..], size)?);
+                        Some(())
+                    });
+                    reply_ok(reply, ok.is_some());
+                }
+                b'm' => match parse_addr_len(args) {
+                    Some((addr, len)) => {
+                        if !read_memory(reply, addr, len) {
+                            reply_ok(reply, false);
+                        }
+                    }
+                    None => reply_ok(reply, false),
+                },
+                b'M' => reply_ok(reply, write_memory_packet(args)),
+                // software breakpoints only, gdb falls back to them for the rest
+                b'Z' | b'z' if args.starts_with(b"0,") => {
+                    let ok = match parse_addr_len(&args[2..]) {
+                        Some((addr, _)) if command == b'Z' => self.breakpoints.insert(addr),
+                        Some((addr, _)) => self.breakpoints.remove(addr),
+                        None => false,
+                    };
+                    reply_ok(reply, ok);
+                }
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        frame.rip = addr;
+                    }
+                    if command == b's' {
+                        frame.rflags |= RFLAGS_TF;
+                    } else {
+                        frame.rflags &= !RFLAGS_TF;
+                    }
+                    return true;
+                }
+                b'D' | b'k' => {
+                    self.breakpoints.remove_all();
+                    frame.rflags &= !RFLAGS_TF;
+                    if command == b'D' {
+                        send(&mut port, "OK");
+                    }
+                    return false;
+                }
+                b'H' | b'T' => reply_ok(reply, true),
+                b'q' if args.starts_with(b"Supported") => {
+                    let _ = write!(reply, "PacketSize={:x};swbreak+", PACKET_SIZE);
+                }
+                b'q' if args == b"Attached" => {
+                    let _ = reply.write_str("1");
+                }
+                // anything else isn't supported, which the empty reply says
+                _ => {}
+            }
+            send(&mut port, reply.as_str());
+        }
+    }
+}
+
+/// stop on `frame` and let gdb look at it. called from `exception_dispatch`
+/// for every exception while the stub is active
+pub fn handle(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    let mut stop_reply: StrBuf<32> = StrBuf::new();
+    let _ = write!(stop_reply, "T{:02x}", signal(frame.vector));
+
+    match frame.vector {
+        1 => debugreg::acknowledge(frame),
+        // `int3` traps, RIP is already past it
+        3 if stub.breakpoints.contains(frame.rip - 1) => {
+            frame.rip -= 1;
+            let _ = stop_reply.write_str("swbreak:;");
+        }
+        _ => {}
+    }
+    frame.rflags &= !RFLAGS_TF;
+
+    if !stub.run(frame, stop_reply.as_str()) {
+        ACTIVE.store(false, Ordering::SeqCst);
+        println!("gdb: detached");
+    }
+}
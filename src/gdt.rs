@@ -3,37 +3,48 @@ use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
+use crate::smp::MAX_CPUS;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const STACK_SIZE: usize = 4096 * 5; // 20,480 (20K)
+
+/// every CPU needs its own double fault stack, TSS and GDT (a TSS is marked
+/// busy when it is loaded, so it can't be shared)
+static mut DOUBLE_FAULT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
+
 // XXX: lazy_static stuff is probably very exploitable 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 20,480 (20K)
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+    static ref TSS: [TaskStateSegment; MAX_CPUS] = {
+        let mut tss = [TaskStateSegment::new(); MAX_CPUS];
+        for (cpu, tss) in tss.iter_mut().enumerate() {
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+                let stack_start = VirtAddr::from_ptr(unsafe {
+                    core::ptr::addr_of!(DOUBLE_FAULT_STACKS[cpu])
+                });
+                stack_start + STACK_SIZE
+            };
+        }
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+    static ref GDT: [(GlobalDescriptorTable, Selectors); MAX_CPUS] = {
+        let mut cpu = 0;
+        [(); MAX_CPUS].map(|_| {
+            let mut gdt = GlobalDescriptorTable::new();
+            let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+            let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS[cpu]));
+            cpu += 1;
+            (
+                gdt,
+                Selectors {
+                    code_selector,
+                    tss_selector,
+                },
+            )
+        })
     };
 }
 
@@ -42,13 +53,20 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+/// load the boot CPU's GDT and TSS
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    init_cpu(0);
+}
+
+/// load the GDT and TSS of CPU `cpu` (the index into `smp::CPUS`, not the APIC id)
+pub fn init_cpu(cpu: usize) {
+    use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let (gdt, selectors) = &GDT[cpu];
+    gdt.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
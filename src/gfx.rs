@@ -0,0 +1,413 @@
+//! 2D drawing on a framebuffer: pixels, lines, rectangles, circles, text and
+//! bitmaps, all clipped to a rectangle
+//!
+//! coordinates are signed so shapes can hang off the edges of the screen,
+//! whatever falls outside the clip rectangle isn't drawn. colors are
+//! 0x00RRGGBB like the framebuffer's pixels.
+
+use crate::cp437;
+use crate::font;
+use crate::framebuffer::Framebuffer;
+
+pub const BLACK: u32 = 0x000000;
+pub const WHITE: u32 = 0xffffff;
+
+pub const fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+/// `over` on top of `under`, `alpha` 0 (`under` only) to 255 (`over` only)
+pub fn blend(under: u32, over: u32, alpha: u8) -> u32 {
+    let alpha = alpha as u32;
+    let mix = |shift: u32| {
+        let under = (under >> shift) & 0xff;
+        let over = (over >> shift) & 0xff;
+        ((over * alpha + under * (255 - alpha)) / 255) << shift
+    };
+    mix(16) | mix(8) | mix(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    /// The part of both rectangles, empty if they don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        Rect::new(x, y, (right - x).max(0), (bottom - y).max(0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 3 bytes a pixel, red first
+    Rgb,
+    /// 4 bytes a pixel, red first, alpha 255 is opaque
+    Rgba,
+}
+
+impl PixelFormat {
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb => 3,
+            PixelFormat::Rgba => 4,
+        }
+    }
+}
+
+/// Pixels to `Canvas::blit`, rows top to bottom without padding.
+pub struct Bitmap<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    data: &'a [u8],
+}
+
+impl<'a> Bitmap<'a> {
+    /// None if `data` is too short for the size.
+    pub fn new(width: usize, height: usize, format: PixelFormat, data: &'a [u8]) -> Option<Bitmap<'a>> {
+        if data.len() < width * height * format.bytes_per_pixel() {
+            return None;
+        }
+        Some(Bitmap { width, height, format, data })
+    }
+
+    /// color and alpha of pixel (x, y)
+    fn pixel(&self, x: usize, y: usize) -> (u32, u8) {
+        let i = (y * self.width + x) * self.format.bytes_per_pixel();
+        let color = rgb(self.data[i], self.data[i + 1], self.data[i + 2]);
+        match self.format {
+            PixelFormat::Rgb => (color, 255),
+            PixelFormat::Rgba => (color, self.data[i + 3]),
+        }
+    }
+}
+
+/// Draws on a framebuffer, only inside the clip rectangle.
+pub struct Canvas<'a> {
+    framebuffer: &'a mut Framebuffer,
+    clip: Rect,
+}
+
+impl<'a> Canvas<'a> {
+    /// A canvas over the whole screen.
+    pub fn new(framebuffer: &'a mut Framebuffer) -> Canvas<'a> {
+        let clip = Rect::new(0, 0, framebuffer.width as i32, framebuffer.height as i32);
+        Canvas { framebuffer, clip }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.framebuffer.width as i32
+    }
+
+    pub fn height(&self) -> i32 {
+        self.framebuffer.height as i32
+    }
+
+    pub fn screen(&self) -> Rect {
+        Rect::new(0, 0, self.width(), self.height())
+    }
+
+    /// Only draws inside `clip` (and the screen) from now on.
+    pub fn set_clip(&mut self, clip: Rect) {
+        self.clip = clip.intersect(&self.screen());
+    }
+
+    pub fn reset_clip(&mut self) {
+        self.clip = self.screen();
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Fills the clip rectangle.
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(self.clip, color);
+    }
+
+    pub fn pixel(&mut self, x: i32, y: i32, color: u32) {
+        if self.clip.contains(x, y) {
+            self.framebuffer.put_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// `color` over the pixel at (x, y) with `alpha` (255 is opaque).
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: u32, alpha: u8) {
+        if !self.clip.contains(x, y) || alpha == 0 {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let color = if alpha == 255 {
+            color
+        } else {
+            blend(self.framebuffer.get_pixel(x, y), color, alpha)
+        };
+        self.framebuffer.put_pixel(x, y, color);
+    }
+
+    /// Bresenham's line, both ends included.
+    /// https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let (mut x, mut y) = (x0, y0);
+        let mut error = dx + dy;
+        loop {
+            self.pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * error;
+            if e2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if e2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    pub fn hline(&mut self, x: i32, y: i32, width: i32, color: u32) {
+        self.fill_rect(Rect::new(x, y, width, 1), color);
+    }
+
+    pub fn vline(&mut self, x: i32, y: i32, height: i32, color: u32) {
+        self.fill_rect(Rect::new(x, y, 1, height), color);
+    }
+
+    /// The outline of `rect`, inside it.
+    pub fn rect(&mut self, rect: Rect, color: u32) {
+        if rect.is_empty() {
+            return;
+        }
+        let Rect { x, y, width, height } = rect;
+        self.hline(x, y, width, color);
+        self.hline(x, y + height - 1, width, color);
+        self.vline(x, y + 1, height - 2, color);
+        self.vline(x + width - 1, y + 1, height - 2, color);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let rect = rect.intersect(&self.clip);
+        if rect.is_empty() {
+            return;
+        }
+        self.framebuffer.fill_rect(rect.x as usize, rect.y as usize,
+            rect.width as usize, rect.height as usize, color);
+    }
+
+    /// The midpoint circle, walks one octant and mirrors it.
+    /// https://en.wikipedia.org/wiki/Midpoint_circle_algorithm
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: i32, color: u32) {
+        let (mut x, mut y) = (radius, 0);
+        let mut error = 1 - radius;
+        while x >= y {
+            for &(px, py) in &[(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                self.pixel(cx + px, cy + py, color);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// `circle` filled, as a horizontal line per row.
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: i32, color: u32) {
+        let (mut x, mut y) = (radius, 0);
+        let mut error = 1 - radius;
+        while x >= y {
+            self.hline(cx - x, cy + y, 2 * x + 1, color);
+            self.hline(cx - x, cy - y, 2 * x + 1, color);
+            self.hline(cx - y, cy + x, 2 * y + 1, color);
+            self.hline(cx - y, cy - x, 2 * y + 1, color);
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Copies a bitmap with its top left corner at (x, y). RGBA pixels are
+    /// blended over what's there.
+    pub fn blit(&mut self, x: i32, y: i32, bitmap: &Bitmap) {
+        let area = Rect::new(x, y, bitmap.width as i32, bitmap.height as i32).intersect(&self.clip);
+        for py in area.y..area.y + area.height {
+            for px in area.x..area.x + area.width {
+                let (color, alpha) = bitmap.pixel((px - x) as usize, (py - y) as usize);
+                self.blend_pixel(px, py, color, alpha);
+            }
+        }
+    }
+
+    /// Writes `text` with the built in font, on `background` if there is one.
+    /// Returns the x after the last character.
+    pub fn text(&mut self, x: i32, y: i32, text: &str, color: u32, background: Option<u32>) -> i32 {
+        let font = &*font::DEFAULT;
+        let (width, height) = (font.width as i32, font.height as i32);
+        let mut x = x;
+        for c in text.chars() {
+            let glyph = font.glyph(cp437::from_char(c).unwrap_or(cp437::FALLBACK) as usize);
+            for gy in 0..height {
+                for gx in 0..width {
+                    if font.pixel(glyph, gx as usize, gy as usize) {
+                        self.pixel(x + gx, y + gy, color);
+                    } else if let Some(background) = background {
+                        self.pixel(x + gx, y + gy, background);
+                    }
+                }
+            }
+            x += width;
+        }
+        x
+    }
+
+    /// Shows what was drawn, see `Framebuffer::present`.
+    pub fn present(&mut self) {
+        self.framebuffer.present();
+    }
+}
+
+const DEMO_FRAMES: i32 = 300;
+const SPRITE_SIZE: usize = 24;
+const GRADIENT_WIDTH: usize = 64;
+const GRADIENT_HEIGHT: usize = 16;
+
+/// A shaded ball with soft edges, to show off alpha blending.
+fn demo_sprite() -> [u8; SPRITE_SIZE * SPRITE_SIZE * 4] {
+    let mut pixels = [0; SPRITE_SIZE * SPRITE_SIZE * 4];
+    let center = SPRITE_SIZE as i32 / 2;
+    let radius2 = center * center;
+    for y in 0..SPRITE_SIZE as i32 {
+        for x in 0..SPRITE_SIZE as i32 {
+            let (dx, dy) = (x - center, y - center);
+            let distance2 = dx * dx + dy * dy;
+            if distance2 >= radius2 {
+                continue;
+            }
+            let i = (y as usize * SPRITE_SIZE + x as usize) * 4;
+            // lighter towards the top left
+            let light = (255 - (dx + dy + 2 * center) * 3).clamp(64, 255) as u8;
+            pixels[i] = light;
+            pixels[i + 1] = light / 2;
+            pixels[i + 2] = 32;
+            pixels[i + 3] = (255 * (radius2 - distance2) / radius2 * 2).min(255) as u8;
+        }
+    }
+    pixels
+}
+
+/// A red to blue strip, to show off plain RGB.
+fn demo_gradient() -> [u8; GRADIENT_WIDTH * GRADIENT_HEIGHT * 3] {
+    let mut pixels = [0; GRADIENT_WIDTH * GRADIENT_HEIGHT * 3];
+    for y in 0..GRADIENT_HEIGHT {
+        for x in 0..GRADIENT_WIDTH {
+            let i = (y * GRADIENT_WIDTH + x) * 3;
+            pixels[i] = (255 - x * 255 / (GRADIENT_WIDTH - 1)) as u8;
+            pixels[i + 1] = (y * 255 / (GRADIENT_HEIGHT - 1)) as u8;
+            pixels[i + 2] = (x * 255 / (GRADIENT_WIDTH - 1)) as u8;
+        }
+    }
+    pixels
+}
+
+/// The `gfxdemo` shell command: a few seconds of animation using every
+/// primitive, double buffered if there is room for it.
+pub fn demo(framebuffer: &mut Framebuffer) {
+    let double_buffered = framebuffer.enable_double_buffering();
+    let sprite_pixels = demo_sprite();
+    let gradient_pixels = demo_gradient();
+    let sprite = Bitmap::new(SPRITE_SIZE, SPRITE_SIZE, PixelFormat::Rgba, &sprite_pixels).unwrap();
+    let gradient = Bitmap::new(GRADIENT_WIDTH, GRADIENT_HEIGHT, PixelFormat::Rgb, &gradient_pixels).unwrap();
+
+    let mut canvas = Canvas::new(framebuffer);
+    let (width, height) = (canvas.width(), canvas.height());
+    let (mut ball_x, mut ball_y, mut speed_x, mut speed_y) = (width / 3, height / 3, 7, 5);
+    let radius = height / 12;
+    let panel = Rect::new(width / 16, height - height / 4, width / 3, height / 6);
+
+    for frame in 0..DEMO_FRAMES {
+        canvas.reset_clip();
+        canvas.clear(rgb(16, 16, 40));
+
+        // a fan of lines whose ends walk along the bottom edge
+        let (cx, cy) = (width / 2, height / 2);
+        for i in 0..16 {
+            let x = (i * width / 16 + frame * 4) % width;
+            canvas.line(cx, cy, x, height - 1, rgb(40, 120 + 8 * i as u8, 200));
+        }
+
+        // nested rectangle outlines
+        for i in 0..8 {
+            let inset = i * 6 + (frame % 24);
+            canvas.rect(Rect::new(inset, inset, width - 2 * inset, height - 2 * inset), rgb(255, 255 - 30 * i as u8, 64));
+        }
+
+        // a ball bouncing off the edges, hanging over them a little
+        ball_x += speed_x;
+        ball_y += speed_y;
+        if ball_x < 0 || ball_x > width {
+            speed_x = -speed_x;
+        }
+        if ball_y < 0 || ball_y > height {
+            speed_y = -speed_y;
+        }
+        canvas.fill_circle(ball_x, ball_y, radius, rgb(220, 40, 60));
+        canvas.circle(ball_x, ball_y, radius + 4, WHITE);
+
+        // the sprites blended over everything else
+        for i in 0..6 {
+            let x = (frame * 3 + i * width / 6) % width;
+            canvas.blit(x, height / 8, &sprite);
+        }
+        canvas.blit(width - GRADIENT_WIDTH as i32 - 16, 16, &gradient);
+
+        // a status panel, what's drawn in it is clipped to its inside
+        canvas.fill_rect(panel, rgb(32, 32, 32));
+        canvas.rect(panel, WHITE);
+        canvas.set_clip(Rect::new(panel.x + 1, panel.y + 1, panel.width - 2, panel.height - 2));
+        canvas.fill_circle(panel.x + (frame * 5) % panel.width, panel.y + panel.height, panel.height / 2, rgb(60, 160, 60));
+        let mut x = canvas.text(panel.x + 8, panel.y + 8, "gfxdemo frame ", WHITE, None);
+        let mut digits = [b'0'; 3];
+        for (i, digit) in digits.iter_mut().rev().enumerate() {
+            *digit += (frame / 10_i32.pow(i as u32) % 10) as u8;
+        }
+        x = canvas.text(x, panel.y + 8, core::str::from_utf8(&digits).unwrap_or(""), rgb(255, 255, 85), None);
+        canvas.text(x, panel.y + 8, if double_buffered { " (double buffered)" } else { "" }, WHITE, None);
+
+        canvas.present();
+    }
+
+    framebuffer.disable_double_buffering();
+}
@@ -4,17 +4,17 @@
 use crate::{gdt, print, println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::vga_buffer::{backspace, cursor_left, cursor_right, 
-    WRITER, BUFFER_WIDTH};
+use crate::lock::Mutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use crate::vga_buffer::{backspace, cursor_left, cursor_right, echo,
+    page_up, page_down, switch_console, foreground_console, WRITER, MAX_WIDTH, LOG_CONSOLE};
 
-use crate::cmd::{PROMPT, handle_cmd};
-use crate::OSINFO;
-use crate::hlt_loop;
+use crate::cmd::{handle_cmd, print_prompt};
 
 use core::result::Result::Ok;
 use core::option::Option::Some;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub const PIC_1_OFFSET: u8 = 32;  /// 0x20 (anything > 0x20 belongs to the APIC)
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // keyboard: 40 - 0x28
@@ -24,6 +24,9 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // keyboard: 40 - 0x28
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Com2 = PIC_1_OFFSET + 3, // IRQ3, COM2 and COM4
+    Com1, // IRQ4, COM1 and COM3
+    Rtc = PIC_2_OFFSET, // IRQ8
 }
 
 
@@ -37,8 +40,46 @@ impl InterruptIndex {
     }
 }
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xA1;
+const PIC_CASCADE_IRQ: u8 = 2;
+
+/// allow an IRQ line (0-15) through the PICs, unmasking the cascade for IRQ 8-15
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let mut pic1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut pic2: Port<u8> = Port::new(PIC_2_DATA);
+    unsafe {
+        if irq < 8 {
+            let mask = pic1.read();
+            pic1.write(mask & !(1 << irq));
+        } else {
+            let mask = pic2.read();
+            pic2.write(mask & !(1 << (irq - 8)));
+            let mask = pic1.read();
+            pic1.write(mask & !(1 << PIC_CASCADE_IRQ));
+        }
+    }
+}
+
+/// block an IRQ line (0-15) at the PICs
+pub fn mask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let (mut port, bit): (Port<u8>, u8) = if irq < 8 {
+        (Port::new(PIC_1_DATA), irq)
+    } else {
+        (Port::new(PIC_2_DATA), irq - 8)
+    };
+    unsafe {
+        let mask = port.read();
+        port.write(mask | (1 << bit));
+    }
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = { /// there are 255 entries
@@ -52,7 +93,10 @@ lazy_static! {
         // just for some hardware
         idt[44].set_handler_fn(int_44_handler);
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // local APIC spurious vector, 0xff isn't covered by the loop above
+        idt[crate::apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[crate::tlb::SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb_shootdown_handler);
+        idt[crate::debugreg::RELOAD_VECTOR as usize].set_handler_fn(debugreg_reload_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -61,28 +105,13 @@ lazy_static! {
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-
-
-
-        // rest of the handlers to prevent double faults
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt.debug.set_handler_fn(debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
-        idt.overflow.set_handler_fn(overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.device_not_available.set_handler_fn(device_not_available_handler);
-        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
-        idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
-        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
+
+        // the rest of the exceptions, with register dumps and recovery
+        crate::exceptions::install(&mut idt);
         // idt.vmm_communication_exception.set_handler_fn(vmm_communication_exception_handler);
-        idt.security_exception.set_handler_fn(security_exception_handler);
 
         idt
     };
@@ -96,15 +125,34 @@ extern "x86-interrupt" fn default_exception_handler(_stack_frame: InterruptStack
     // println!("default_exception_handler");
 }
 
-extern "x86-interrupt" fn int_44_handler(_stack_frame: InterruptStackFrame) {
-    println!("interrupt 44");
+/// the local APIC raises this when an interrupt went away before it could be
+/// delivered, it must not be acknowledged with an EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+}
+
+/// another CPU changed a mapping we may have cached, see `tlb::shootdown`
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter();
+    crate::percpu::current().stats.ipis.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::tlb::handle_shootdown();
+    crate::apic::eoi();
+    crate::percpu::irq_exit();
 }
 
+/// the watchpoints changed on another CPU, see `debugreg::set`
+extern "x86-interrupt" fn debugreg_reload_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter();
+    crate::percpu::current().stats.ipis.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::debugreg::load();
+    crate::apic::eoi();
+    crate::percpu::irq_exit();
+}
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+extern "x86-interrupt" fn int_44_handler(_stack_frame: InterruptStackFrame) {
+    println!("interrupt 44");
 }
 
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
@@ -114,29 +162,38 @@ extern "x86-interrupt" fn double_fault_handler(
 
 
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    // crate::kernel_main(OSINFO.lock().bootinfo);
-
-    // read from it to dump some bytes
-    // TODO: convert this dump functionality into a macro?
+
+lazy_static! {
+    pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+        "KEYBOARD",
+        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+    );
+}
+
+/// `process_keyevent` keeps the modifiers to itself, Shift+PageUp and
+/// Alt+F1 need them
+static SHIFT: AtomicBool = AtomicBool::new(false);
+static ALT: AtomicBool = AtomicBool::new(false);
+
+/// for `exceptions::task_killed`: a killed task may have been an interrupt
+/// handler (shell commands run in the keyboard one), acknowledge whatever it
+/// didn't get to and drop the keyboard state it may have held
+pub fn abandon_pic_interrupts() {
     unsafe {
-        for i in 0..32 {
-            let ptr = stack_frame.instruction_pointer.as_u64() + i;
-            print!("{:#04x} ", *(ptr as *mut u8));
-        }
+        KEYBOARD.force_unlock();
+        let mut pics = PICS.lock();
+        pics.notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        pics.notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
-    hlt_loop();
 }
 
+/// forget any half typed key sequence (shift, an 0xe0 prefix) after a restart
+pub fn reset_keyboard() {
+    unsafe { KEYBOARD.force_unlock() };
+    *KEYBOARD.lock() = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    SHIFT.store(false, Ordering::Relaxed);
+    ALT.store(false, Ordering::Relaxed);
+}
 
 /// dfeault interrupt handler just to prevent segment not present exceptions
 extern "x86-interrupt" fn default_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -145,33 +202,61 @@ extern "x86-interrupt" fn default_interrupt_handler(_stack_frame: InterruptStack
 
 /// timer handler, maybe shouldn't do anything?
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!("_");// 0x8 is backspace
-    for _i in 0..20000 { // to generate "blink" effect!
+    crate::percpu::irq_enter();
+    crate::time::tick();
+    crate::percpu::current().stats.timer_ticks.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::watchdog::pet();
 
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    crate::percpu::irq_exit();
+}
 
-    backspace(true);
+/// RTC periodic interrupt (IRQ8), only unmasked by `rtc::enable_periodic_interrupt`
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter();
+    crate::rtc::handle_interrupt();
 
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+    crate::percpu::irq_exit();
+}
+
+/// a UART on IRQ4 received something, only unmasked by `serial::enable_receive`
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter();
+    crate::serial::handle_interrupt(crate::serial::COM1_COM3_IRQ);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
     }
+    crate::percpu::irq_exit();
 }
 
+/// the same for IRQ3
+extern "x86-interrupt" fn com2_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::percpu::irq_enter();
+    crate::serial::handle_interrupt(crate::serial::COM2_COM4_IRQ);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com2.as_u8());
+    }
+    crate::percpu::irq_exit();
+}
 
 /// read keyboard input and do stuff (selector/entry 40)
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
-    use spin::Mutex;
+    use pc_keyboard::{DecodedKey, KeyCode, KeyState};
     use x86_64::instructions::port::Port;
     
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
-    }
-
+    crate::percpu::irq_enter();
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
 
@@ -179,12 +264,22 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     // scancode 14 is backspace and 83 is delete
     // unicde 0x08 is backspace and 0x7f is delete
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        match key_event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight =>
+                SHIFT.store(key_event.state == KeyState::Down, Ordering::Relaxed),
+            KeyCode::AltLeft | KeyCode::AltRight =>
+                ALT.store(key_event.state == KeyState::Down, Ordering::Relaxed),
+            _ => {}
+        }
+        // the log console only shows output, all it takes is scrolling and switching away
+        let read_only = foreground_console() == LOG_CONSOLE;
 
         if let Some(key) = keyboard.process_keyevent(key_event) {
             // print!("{:?}{:?} ", scancode, key);
             // if scancode == 
 
             match key {
+                DecodedKey::Unicode(_) if read_only => {}
                 DecodedKey::Unicode(character)  => {
 
                     // TODO: handle backspace and del properly in vga_buffer
@@ -196,28 +291,44 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
                         backspace(false);
                     } else if character == '\n' {
                         // run command
-                        let mut line: [char; BUFFER_WIDTH] = [0 as char;80];
-                        WRITER.lock().get_prev_line(&mut line);
+                        let mut line: [char; MAX_WIDTH] = [0 as char; MAX_WIDTH];
+                        let len = WRITER.lock().get_prev_line(&mut line);
+                        let line = &line[..len];
                         
                         println!();
                         print!("<");
-                        for c in line {
+                        for &c in line {
                            
                             if c != '\0' {
                                  print!("{}", c);
                             }
 
                         }
-                        handle_cmd(&line);
-                        print!("{}",PROMPT);
+                        handle_cmd(line);
+                        print_prompt();
                     } else {
-                        
-                        print!("{}", character);
+                        // not for the log, it gets the whole line with the output
+                        echo(character);
                     }
                 }
 
                 DecodedKey::RawKey(key) => {
                     match key {
+                        KeyCode::PageUp if SHIFT.load(Ordering::Relaxed) => page_up(),
+                        KeyCode::PageDown if SHIFT.load(Ordering::Relaxed) => page_down(),
+                        KeyCode::F1 | KeyCode::F2 | KeyCode::F3 | KeyCode::F4 if ALT.load(Ordering::Relaxed) => {
+                            let console = match key {
+                                KeyCode::F1 => 0,
+                                KeyCode::F2 => 1,
+                                KeyCode::F3 => 2,
+                                _ => 3,
+                            };
+                            // a shell console gets its prompt when it's first shown
+                            if switch_console(console) && console != LOG_CONSOLE {
+                                print_prompt();
+                            }
+                        }
+                        _ if read_only => {}
                         KeyCode::ArrowLeft => cursor_left(),
                         KeyCode::ArrowRight => cursor_right(),
                         _=> print!("({:?})", key),
@@ -233,148 +344,10 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+    crate::percpu::irq_exit();
 }
 
 
-extern "x86-interrupt" fn divide_error_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: Divide Error");
-    println!("{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn debug_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: Debug Error");
-    println!("{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn non_maskable_interrupt_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: non_maskable_interrupt Error");
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn overflow_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: Overflow Error");
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn bound_range_exceeded_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: bound_range_exceeded");
-    println!("{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn invalid_opcode_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: invalid_opcode");
-    println!("{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn device_not_available_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: device_not_available");
-    println!("{:#?}", stack_frame);
-}
-
-
-
-extern "x86-interrupt" fn invalid_tss_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: invalid_tss: errcode {}",ec);
-    println!("{:#?}", stack_frame);
-}
-
-
-
-extern "x86-interrupt" fn segment_not_present_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: segment_not_present: errcode {}", ec);
-    println!("External?:{} Table:{} Index:{}", ec & 1, ec & 0b110, (ec & 0b111111000) >> 3);
-    println!("{:#?}", stack_frame);
-    // read from it to dump some bytes
-    unsafe {
-        for i in 0..32 {
-            let ptr = stack_frame.instruction_pointer.as_u64() + i;
-            print!("{:#04x} ", *(ptr as *mut u8));
-        }
-    }
-    // restore keyboard and timer interrupts
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-    //restart?
-    crate::kernel_main(OSINFO.lock().bootinfo);
-
-
-}
-
-
-extern "x86-interrupt" fn stack_segment_fault_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: stack_segment_fault: errcode {}", ec);
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn general_protection_fault_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: general_protection_fault errcode {}", ec);
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn x87_floating_point_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: x87_floating_point");
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn alignment_check_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: alignment_check errcode {}", ec);
-    println!("{:#?}", stack_frame);
-}
-
-
-
-
-extern "x86-interrupt" fn simd_floating_point_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: simd_floating_point");
-    println!("{:#?}", stack_frame);
-}
-
-
-extern "x86-interrupt" fn virtualization_handler (
-    stack_frame: InterruptStackFrame) {
-
-    println!("EXCEPTION: virtualization");
-    println!("{:#?}", stack_frame);
-}
-
 // extern "x86-interrupt" fn vmm_communication_exception_handler (
 //     stack_frame: InterruptStackFrame) {
 
@@ -382,13 +355,6 @@ extern "x86-interrupt" fn virtualization_handler (
 //     println!("{:#?}", stack_frame);
 // }
 
-extern "x86-interrupt" fn security_exception_handler (
-    stack_frame: InterruptStackFrame, ec:u64) {
-
-    println!("EXCEPTION: security_exception errcode {}", ec);
-    println!("{:#?}", stack_frame);
-}
-
 // #[test_case]
 // fn test_breakpoint_exception() {
 //     // invoke a breakpoint exception
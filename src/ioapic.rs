@@ -0,0 +1,116 @@
+//! I/O APIC
+//! https://wiki.osdev.org/IOAPIC
+//!
+//! the legacy IRQs still go through the 8259 PICs, the I/O APIC is only used
+//! for extra routes next to them, like the PIT as NMI for `watchdog`. every
+//! entry comes out of reset masked, so the pins we don't touch stay quiet.
+//! only the first I/O APIC in the MADT is used, it has the ISA IRQs.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use crate::{acpi, memory};
+
+/// register select and data window, relative to the base
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+/// two 32 bit registers per entry
+const REG_REDIRECTION: u32 = 0x10;
+
+const DELIVERY_NMI: u64 = 0b100 << 8;
+const POLARITY_LOW: u64 = 1 << 13;
+const MASKED: u64 = 1 << 16;
+/// physical destination 0xff reaches every local APIC
+const DESTINATION_BROADCAST: u64 = 0xff << 56;
+
+/// MADT interrupt override flags
+const OVERRIDE_POLARITY_MASK: u16 = 0b11;
+const OVERRIDE_POLARITY_LOW: u16 = 0b11;
+
+/// virtual address of the registers, 0 until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+static GSI_BASE: AtomicU32 = AtomicU32::new(0);
+
+fn read(reg: u32) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        core::ptr::write_volatile((base + IOREGSEL) as *mut u32, reg);
+        core::ptr::read_volatile((base + IOWIN) as *const u32)
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        core::ptr::write_volatile((base + IOREGSEL) as *mut u32, reg);
+        core::ptr::write_volatile((base + IOWIN) as *mut u32, value);
+    }
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// map the first I/O APIC from the MADT. has to run after `memory::install`
+pub fn init() -> bool {
+    let ioapic = match acpi::madt().and_then(|madt| madt.io_apics().first().copied()) {
+        Some(ioapic) => ioapic,
+        None => return false,
+    };
+    let virt = memory::map_mmio(PhysAddr::new(ioapic.address as u64), 4096);
+    GSI_BASE.store(ioapic.gsi_base, Ordering::Relaxed);
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+    true
+}
+
+/// how many pins this I/O APIC has
+pub fn entries() -> u32 {
+    ((read(REG_VERSION) >> 16) & 0xff) + 1
+}
+
+/// the entry index of `gsi`, if it's on this I/O APIC
+fn index(gsi: u32) -> Option<u32> {
+    let index = gsi.checked_sub(GSI_BASE.load(Ordering::Relaxed))?;
+    if is_initialized() && index < entries() { Some(index) } else { None }
+}
+
+fn write_entry(index: u32, entry: u64) {
+    let reg = REG_REDIRECTION + index * 2;
+    // masked while the halves don't match
+    write(reg, MASKED as u32);
+    write(reg + 1, (entry >> 32) as u32);
+    write(reg, entry as u32);
+}
+
+/// deliver ISA `irq` as an NMI to every CPU, as well as through the PIC
+pub fn route_isa_irq_as_nmi(irq: u8) -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    let gsi = madt.isa_irq_to_gsi(irq);
+    let index = match index(gsi) {
+        Some(index) => index,
+        None => return false,
+    };
+    // ISA IRQs are active high unless an override says otherwise. NMIs
+    // have to be edge triggered either way, which is the 0 in bit 15
+    let low = madt.overrides().iter().any(|o| {
+        o.bus == 0 && o.source == irq && o.flags & OVERRIDE_POLARITY_MASK == OVERRIDE_POLARITY_LOW
+    });
+    let mut entry = DELIVERY_NMI | DESTINATION_BROADCAST;
+    if low {
+        entry |= POLARITY_LOW;
+    }
+    write_entry(index, entry);
+    true
+}
+
+/// stop delivering ISA `irq` through the I/O APIC
+pub fn mask_isa_irq(irq: u8) {
+    let gsi = acpi::madt().map(|madt| madt.isa_irq_to_gsi(irq)).unwrap_or(irq as u32);
+    if let Some(index) = index(gsi) {
+        write_entry(index, MASKED);
+    }
+}
@@ -1,32 +1,54 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 #![feature(asm)]
+#![feature(global_asm)]
 
 pub mod gdt;
 pub mod interrupts;
-// pub mod serial;
+pub mod exceptions;
+pub mod serial;
 pub mod vga_buffer;
+pub mod ansi;
+pub mod cp437;
+pub mod font;
+pub mod framebuffer;
+pub mod text_mode;
+pub mod gfx;
 pub mod cmd;
 pub mod strutils;
 pub mod memory;
+pub mod time;
+pub mod rtc;
+pub mod acpi;
+pub mod power;
+pub mod apic;
+pub mod smp;
+pub mod percpu;
+pub mod tlb;
+pub mod symbols;
+pub mod backtrace;
+pub mod disas;
+pub mod memtools;
+pub mod debugreg;
+pub mod gdbstub;
+pub mod ioapic;
+pub mod watchdog;
+pub mod lock;
 
-use lazy_static::lazy_static;
 use bootloader::{BootInfo,entry_point};
-use spin::Mutex;
+use crate::lock::Mutex;
+use crate::vga_buffer::WARNING_COLOR;
 
 
 
-lazy_static! {
-
-    pub static ref OSINFO: Mutex<OSInfoStore> = Mutex::new(OSInfoStore {
-        bootinfo: unsafe { &mut *(0x0 as *mut BootInfo) },
-    });
-
-}
+pub static OSINFO: Mutex<OSInfoStore> = Mutex::new("OSINFO", OSInfoStore {
+    bootinfo: None,
+});
 
 pub struct OSInfoStore {
     // TODO: track row position too?
-    bootinfo: &'static BootInfo,
+    /// set by `kernel_main`, None before that
+    bootinfo: Option<&'static BootInfo>,
 }
 
 /// read msr , 11 is if APIC is supported 
@@ -72,7 +94,7 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let (level_4_page_table, _) = Cr3::read();
     println!("Level 4 page table at: {:?}", level_4_page_table.start_address());
 
-    OSINFO.lock().bootinfo = &boot_info;
+    OSINFO.lock().bootinfo = Some(boot_info);
     // println!("boot info: {:#?}", boot_info);
 
     // insert breakpoint, should trigger interrupt
@@ -90,6 +112,10 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
+    if !acpi::init() {
+        println_colored!(WARNING_COLOR, "ACPI: no RSDP found");
+    }
+
 
     // map an unused (virtual) page
     let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
@@ -104,11 +130,20 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // mapping back the kernel page
     memory::create_mapping(page, &mut mapper, &mut frame_allocator, 0x200000, 0x240000, Flags::PRESENT);
 
+    // from here on the rest of the kernel owns the page table
+    memory::install(mapper, frame_allocator);
 
-    print!("{}", crate::cmd::PROMPT);
+    smp::init(&boot_info.memory_map);
+
+    if !watchdog::enable(watchdog::DEFAULT_TIMEOUT_SECS) {
+        println_colored!(WARNING_COLOR, "watchdog: no I/O APIC, lockups won't be detected");
+    }
+
+
+    cmd::print_prompt();
 
     
-    hlt_loop();
+    smp::idle_loop();
 }
 
 
@@ -118,9 +153,53 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// what's left of `kernel_main` after a soft restart. drivers that keep state
+/// of their own start over, memory, ACPI and the other CPUs stay as they are
+extern "C" fn restart_main() -> ! {
+    if percpu::cpu_id() != 0 {
+        // only the boot CPU runs the shell and owns the legacy devices
+        exceptions::reset();
+        lock::reset_held();
+        percpu::current().reset_irq_depth();
+        x86_64::instructions::interrupts::enable();
+        smp::idle_loop();
+    }
+
+    // whatever got abandoned may have been holding these
+    unsafe { vga_buffer::WRITER.force_unlock() };
+    serial::unjam();
+    interrupts::abandon_pic_interrupts();
+    interrupts::reset_keyboard();
+    percpu::current().reset_irq_depth();
+    exceptions::reset();
+    lock::reset_held();
+
+    rtc::disable_periodic_interrupt();
+    interrupts::init_idt();
+    unsafe { interrupts::PICS.lock().initialize() };
+    serial::init();
+    rtc::sync();
+
+    vga_buffer::WRITER.lock().reset();
+    println!("Hello again to #OS prerelease (soft restart, up {}ms).", time::uptime_ms());
+    cmd::print_prompt();
+
+    x86_64::instructions::interrupts::enable();
+    smp::idle_loop();
+}
+
+/// bring the shell and drivers back to where they were after boot without
+/// going through the bootloader again. continues on this CPU's recovery
+/// stack, whatever called this is abandoned
+pub fn soft_restart() -> ! {
+    exceptions::run_on_recovery_stack(restart_main);
+}
+
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }; // new
+    serial::init();
+    rtc::init();
     x86_64::instructions::interrupts::enable();     // should be sti - enable interrupt
 }
\ No newline at end of file
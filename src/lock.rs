@@ -0,0 +1,413 @@
+//! kernel mutex with optional lock debugging
+//!
+//! a `spin::Mutex` with a name. built with the `lockdebug` feature it also
+//! keeps track of
+//!
+//! - which CPU holds it and where it was locked, for `holder()` and the
+//!   watchdog report
+//! - locking it again on the CPU that holds it, typically from an interrupt
+//!   handler that interrupted the holder. that can only spin forever, so it's
+//!   reported with both locations and panics
+//! - lock order, lockdep style: every lock taken while another one is held
+//!   records "held before" between the two. taking them the other way round
+//!   later is a possible deadlock between two CPUs and gets reported, as does
+//!   a lock used both in interrupt context and with interrupts enabled
+//!
+//! each lock is its own class, they're all statics. order and interrupt
+//! reports can't be printed while the console lock may be held, so they wait
+//! until the CPU holds no locks at all. without the feature all of this is
+//! compiled out and `Mutex` is just the spin lock.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+pub struct Mutex<T> {
+    name: &'static str,
+    #[cfg(feature = "lockdebug")]
+    state: debug::LockState,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: 'a> {
+    #[cfg(feature = "lockdebug")]
+    lock: &'a Mutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Mutex<T> {
+        Mutex {
+            name,
+            #[cfg(feature = "lockdebug")]
+            state: debug::LockState::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[cfg_attr(feature = "lockdebug", track_caller)]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdebug")]
+        let location = core::panic::Location::caller();
+        #[cfg(feature = "lockdebug")]
+        self.state.before_lock(self.name, location);
+
+        let guard = self.inner.lock();
+
+        #[cfg(feature = "lockdebug")]
+        self.state.acquired(self.name, location);
+        MutexGuard {
+            #[cfg(feature = "lockdebug")]
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdebug", track_caller)]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+
+        #[cfg(feature = "lockdebug")]
+        self.state.acquired(self.name, core::panic::Location::caller());
+        Some(MutexGuard {
+            #[cfg(feature = "lockdebug")]
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// whether anyone holds it right now, which may have changed by the time
+    /// this returns
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
+
+    /// CPU and location of whoever holds it, always None without `lockdebug`
+    pub fn holder(&self) -> Option<(usize, &'static core::panic::Location<'static>)> {
+        #[cfg(feature = "lockdebug")]
+        return self.state.holder();
+        #[cfg(not(feature = "lockdebug"))]
+        None
+    }
+
+    /// # Safety
+    /// only for holders that will never unlock it, like an abandoned task
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lockdebug")]
+        self.state.released();
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // the owner has to be cleared before someone else can get it
+        #[cfg(feature = "lockdebug")]
+        self.lock.state.released();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(feature = "lockdebug")]
+        debug::print_pending();
+    }
+}
+
+/// forget the locks this CPU held, for when whatever held them was abandoned
+/// (`exceptions::task_killed`, `soft_restart`). their owners are cleared by
+/// the `force_unlock` that has to go with this
+pub fn reset_held() {
+    #[cfg(feature = "lockdebug")]
+    debug::reset_held();
+}
+
+#[cfg(feature = "lockdebug")]
+mod debug {
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+    use crate::smp::MAX_CPUS;
+    use crate::{backtrace, exceptions, percpu, println, vga_buffer};
+
+    /// more than this many locks just aren't tracked
+    const MAX_CLASSES: usize = 64;
+    const NO_CLASS: usize = usize::MAX;
+    /// nesting depth of locks on one CPU we keep track of
+    const MAX_HELD: usize = 16;
+
+    const USED_IN_IRQ: u8 = 1 << 0;
+    const USED_WITH_IRQS_ON: u8 = 1 << 1;
+    const IRQ_REPORTED: u8 = 1 << 2;
+
+    /// the kinds of report that wait in `Held::pending`
+    const PENDING_ORDER: u64 = 1;
+    const PENDING_IRQ: u64 = 2;
+
+    fn location(ptr: usize) -> Option<&'static Location<'static>> {
+        if ptr == 0 { None } else { Some(unsafe { &*(ptr as *const Location<'static>) }) }
+    }
+
+    fn location_ptr(location: &'static Location<'static>) -> usize {
+        location as *const Location<'static> as usize
+    }
+
+    pub struct LockState {
+        /// CPU id + 1 of the holder, 0 while free
+        owner: AtomicUsize,
+        /// `Location` of the `lock()` that took it
+        location: AtomicUsize,
+        /// index + 1 into `CLASSES`, 0 until the first lock
+        class: AtomicUsize,
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO_ROW: [AtomicUsize; MAX_CLASSES] = [ZERO; MAX_CLASSES];
+
+    struct Class {
+        /// the name of the lock, as pointer and length so it can be atomic
+        name_ptr: AtomicUsize,
+        name_len: AtomicUsize,
+        /// bit n: class n was held when this one was taken
+        after: AtomicU64,
+        /// bit n: an inversion with class n has been reported
+        reported: AtomicU64,
+        irq: AtomicU8,
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CLASS_INIT: Class = Class {
+        name_ptr: AtomicUsize::new(0),
+        name_len: AtomicUsize::new(0),
+        after: AtomicU64::new(0),
+        reported: AtomicU64::new(0),
+        irq: AtomicU8::new(0),
+    };
+
+    static CLASSES: [Class; MAX_CLASSES] = [CLASS_INIT; MAX_CLASSES];
+    static NEXT_CLASS: AtomicUsize = AtomicUsize::new(0);
+    /// [a][b]: where b was first taken while a was held
+    static EDGE_LOCATION: [[AtomicUsize; MAX_CLASSES]; MAX_CLASSES] = [ZERO_ROW; MAX_CLASSES];
+
+    fn class_name(class: usize) -> &'static str {
+        let c = &CLASSES[class];
+        let ptr = c.name_ptr.load(Ordering::Acquire) as *const u8;
+        if ptr.is_null() {
+            return "?";
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, c.name_len.load(Ordering::Acquire)) };
+        core::str::from_utf8(bytes).unwrap_or("?")
+    }
+
+    /// per CPU: the classes held, innermost last, and one report waiting
+    /// until they're all released
+    struct Held {
+        classes: [AtomicUsize; MAX_HELD],
+        depth: AtomicUsize,
+        /// set while printing a report, whose own locking isn't checked
+        reporting: AtomicBool,
+        pending: AtomicU64,
+        pending_location: AtomicUsize,
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const HELD_INIT: Held = Held {
+        classes: [ZERO; MAX_HELD],
+        depth: AtomicUsize::new(0),
+        reporting: AtomicBool::new(false),
+        pending: AtomicU64::new(0),
+        pending_location: AtomicUsize::new(0),
+    };
+
+    static HELD: [Held; MAX_CPUS] = [HELD_INIT; MAX_CPUS];
+
+    impl LockState {
+        pub const fn new() -> LockState {
+            LockState { owner: AtomicUsize::new(0), location: AtomicUsize::new(0), class: AtomicUsize::new(0) }
+        }
+
+        fn class(&self, name: &'static str) -> usize {
+            let class = self.class.load(Ordering::Acquire);
+            if class != 0 {
+                return class - 1;
+            }
+            let new = NEXT_CLASS.fetch_add(1, Ordering::Relaxed);
+            if new >= MAX_CLASSES {
+                return NO_CLASS;
+            }
+            CLASSES[new].name_len.store(name.len(), Ordering::Release);
+            CLASSES[new].name_ptr.store(name.as_ptr() as usize, Ordering::Release);
+            match self.class.compare_exchange(0, new + 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => new,
+                // another CPU registered it first, `new` stays unused
+                Err(class) => class - 1,
+            }
+        }
+
+        pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+            let owner = self.owner.load(Ordering::Acquire);
+            let location = location(self.location.load(Ordering::Acquire))?;
+            if owner == 0 { None } else { Some((owner - 1, location)) }
+        }
+
+        pub fn before_lock(&self, name: &'static str, location: &'static Location<'static>) {
+            let cpu = percpu::cpu_id();
+            let held = &HELD[cpu];
+            if held.reporting.load(Ordering::Relaxed) {
+                return;
+            }
+            if self.owner.load(Ordering::Acquire) == cpu + 1 {
+                self.recursion(name, cpu, location);
+            }
+
+            let class = self.class(name);
+            if class == NO_CLASS {
+                return;
+            }
+            let depth = held.depth.load(Ordering::Relaxed).min(MAX_HELD);
+            for slot in &held.classes[..depth] {
+                let before = slot.load(Ordering::Relaxed);
+                if before == NO_CLASS || before == class {
+                    continue;
+                }
+                if CLASSES[class].after.fetch_or(1 << before, Ordering::Relaxed) & (1 << before) == 0 {
+                    EDGE_LOCATION[before][class].store(location_ptr(location), Ordering::Relaxed);
+                }
+                // taken the other way round before?
+                if CLASSES[before].after.load(Ordering::Relaxed) & (1 << class) != 0
+                    && CLASSES[class].reported.load(Ordering::Relaxed) & (1 << before) == 0
+                {
+                    queue(held, PENDING_ORDER << 32 | (before as u64) << 16 | class as u64, location);
+                }
+            }
+        }
+
+        pub fn acquired(&self, name: &'static str, location: &'static Location<'static>) {
+            let cpu = percpu::cpu_id();
+            self.location.store(location_ptr(location), Ordering::Relaxed);
+            self.owner.store(cpu + 1, Ordering::Release);
+
+            let held = &HELD[cpu];
+            let class = self.class(name);
+            let depth = held.depth.fetch_add(1, Ordering::Relaxed);
+            if depth < MAX_HELD {
+                held.classes[depth].store(class, Ordering::Relaxed);
+            }
+            if class == NO_CLASS || held.reporting.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let used = if percpu::in_interrupt() {
+                USED_IN_IRQ
+            } else if x86_64::instructions::interrupts::are_enabled() {
+                USED_WITH_IRQS_ON
+            } else {
+                0
+            };
+            let irq = CLASSES[class].irq.fetch_or(used, Ordering::Relaxed) | used;
+            if irq & (USED_IN_IRQ | USED_WITH_IRQS_ON | IRQ_REPORTED) == USED_IN_IRQ | USED_WITH_IRQS_ON {
+                queue(held, PENDING_IRQ << 32 | class as u64, location);
+            }
+        }
+
+        pub fn released(&self) {
+            let owner = self.owner.swap(0, Ordering::Release);
+            if owner == 0 {
+                return;
+            }
+            // usually the innermost one, but guards can be dropped in any order
+            let held = &HELD[owner - 1];
+            let class = self.class.load(Ordering::Relaxed).wrapping_sub(1);
+            let depth = held.depth.load(Ordering::Relaxed);
+            let tracked = depth.min(MAX_HELD);
+            if let Some(i) = (0..tracked).rev().find(|&i| held.classes[i].load(Ordering::Relaxed) == class) {
+                for j in i..tracked - 1 {
+                    held.classes[j].store(held.classes[j + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+            }
+            held.depth.store(depth.saturating_sub(1), Ordering::Relaxed);
+        }
+
+        /// this CPU already holds it and would spin forever
+        fn recursion(&self, name: &str, cpu: usize, location: &'static Location<'static>) -> ! {
+            HELD[cpu].reporting.store(true, Ordering::Relaxed);
+            exceptions::unjam_console();
+            let _color = vga_buffer::set_foreground(vga_buffer::ERROR_COLOR);
+            println!("LOCK: recursive locking of {} on CPU {}", name, cpu);
+            if let Some((_, first)) = self.holder() {
+                println!("held since {}", first);
+            }
+            println!("taken again at {}{}", location,
+                if percpu::in_interrupt() { " in an interrupt handler" } else { "" });
+            backtrace::print();
+            panic!("recursive locking of {}", name);
+        }
+    }
+
+    fn queue(held: &Held, report: u64, location: &'static Location<'static>) {
+        // one at a time, later ones are found again next time
+        if held.pending.compare_exchange(0, report, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            held.pending_location.store(location_ptr(location), Ordering::Relaxed);
+        }
+    }
+
+    /// print the waiting report once this CPU holds no locks
+    pub fn print_pending() {
+        let held = &HELD[percpu::cpu_id()];
+        if held.depth.load(Ordering::Relaxed) != 0 || held.reporting.load(Ordering::Relaxed) {
+            return;
+        }
+        let report = held.pending.swap(0, Ordering::Relaxed);
+        if report == 0 {
+            return;
+        }
+        let at = location(held.pending_location.load(Ordering::Relaxed));
+
+        held.reporting.store(true, Ordering::Relaxed);
+        let color = vga_buffer::set_foreground(vga_buffer::WARNING_COLOR);
+        let class = (report & 0xffff) as usize;
+        if report >> 32 == PENDING_ORDER {
+            let before = ((report >> 16) & 0xffff) as usize;
+            CLASSES[class].reported.fetch_or(1 << before, Ordering::Relaxed);
+            CLASSES[before].reported.fetch_or(1 << class, Ordering::Relaxed);
+            println!("LOCK: possible deadlock, lock order inversion between {} and {}",
+                class_name(before), class_name(class));
+            if let Some(at) = at {
+                println!("{} taken while holding {} at {}", class_name(class), class_name(before), at);
+            }
+            if let Some(earlier) = location(EDGE_LOCATION[class][before].load(Ordering::Relaxed)) {
+                println!("{} taken while holding {} at {}", class_name(before), class_name(class), earlier);
+            }
+        } else {
+            CLASSES[class].irq.fetch_or(IRQ_REPORTED, Ordering::Relaxed);
+            println!("LOCK: {} is taken in interrupt handlers and with interrupts enabled, \
+                an interrupt can deadlock on it", class_name(class));
+            if let Some(at) = at {
+                println!("last taken at {}", at);
+            }
+        }
+        drop(color);
+        held.reporting.store(false, Ordering::Relaxed);
+    }
+
+    pub fn reset_held() {
+        let held = &HELD[percpu::cpu_id()];
+        held.depth.store(0, Ordering::Relaxed);
+        held.reporting.store(false, Ordering::Relaxed);
+    }
+}
+
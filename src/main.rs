@@ -2,7 +2,7 @@
 #![no_main]
 
 use core::panic::PanicInfo;
-use hash_os::println;
+use hash_os::{println, vga_buffer};
 
 
 
@@ -10,6 +10,8 @@ use hash_os::println;
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let _color = vga_buffer::set_foreground(vga_buffer::ERROR_COLOR);
     println!("{}", info);
+    hash_os::backtrace::print();
     loop {}
 }
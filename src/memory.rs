@@ -6,7 +6,22 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, Translate, UnmapError};
 use crate::println;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::lock::Mutex;
+
+/// where the bootloader mapped the complete physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Frames below 1MiB are never handed out by `BootInfoFrameAllocator`, real mode
+/// code like the AP trampoline has to live there.
+pub const LOW_MEMORY_END: u64 = 0x100000;
+
+/// The kernel's page table and frame allocator once `kernel_main` is done with
+/// its own mappings, see `install`.
+static KERNEL_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new("KERNEL_MAPPER", None);
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new("FRAME_ALLOCATOR", None);
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -15,10 +30,26 @@ use crate::println;
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the virtual address a physical address is reachable at through the
+/// bootloader's mapping of the complete physical memory.
+///
+/// Only meaningful after `init` has been called.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Like `phys_to_virt`, but None for physical addresses past the end of the
+/// virtual address space the mapping starts in, instead of panicking.
+pub fn try_phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    let virt = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed).checked_add(addr.as_u64())?;
+    VirtAddr::try_new(virt).ok()
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -78,6 +109,178 @@ pub fn create_mapping(page: Page,
 
 }
 
+/// Hands the page table and frame allocator over to the rest of the kernel.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Runs `f` with the kernel page table and frame allocator, returns `None` if
+/// `install` hasn't been called yet.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    let mut mapper = KERNEL_MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => Some(f(mapper, frame_allocator)),
+        _ => None,
+    }
+}
+
+/// Maps the frame at the same virtual address, used for code that runs
+/// while paging is being switched on (the AP trampoline).
+pub fn identity_map(frame: PhysFrame, flags: Flags) -> Result<(), MapToError<Size4KiB>> {
+    let result = with_mapper(|mapper, frame_allocator| {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            // already there from an earlier call
+            Err(MapToError::PageAlreadyMapped(f)) if f == frame => Ok(()),
+            Err(e) => Err(e),
+        }
+    });
+    result.unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+/// The flags that apply to `addr` in the active page table, `None` if it isn't
+/// mapped. WRITABLE and USER_ACCESSIBLE are only set if every level allows
+/// them, NO_EXECUTE if any level sets it. Walks the tables by hand instead of
+/// going through `with_mapper`, so exception handlers can use it even when
+/// they interrupted whoever holds the mapper.
+pub fn page_flags(addr: VirtAddr) -> Option<Flags> {
+    use x86_64::registers::control::Cr3;
+
+    if PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+    let mut flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        let table: &PageTable = unsafe { &*phys_to_virt(table_addr).as_ptr() };
+        let entry = &table[index];
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(Flags::PRESENT) {
+            return None;
+        }
+        flags &= entry_flags | Flags::NO_EXECUTE;
+        flags |= entry_flags & Flags::NO_EXECUTE;
+        // 1GiB and 2MiB pages end the walk early
+        if (level == 1 || level == 2) && entry_flags.contains(Flags::HUGE_PAGE) {
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    Some(flags)
+}
+
+/// Whether `addr` is mapped in the active page table, see `page_flags`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    page_flags(addr).is_some()
+}
+
+/// Whether every byte of `len` bytes from `addr` is mapped, and writable if
+/// `write` is set.
+pub fn range_accessible(addr: VirtAddr, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let last = match addr.as_u64().checked_add(len - 1).map(VirtAddr::try_new) {
+        Some(Ok(last)) => last,
+        _ => return false,
+    };
+    let mut page = addr.align_down(4096u64).as_u64();
+    while page <= last.as_u64() {
+        // a range across the non-canonical hole is never accessible
+        let flags = VirtAddr::try_new(page).ok().and_then(page_flags);
+        match flags {
+            Some(flags) if !write || flags.contains(Flags::WRITABLE) => {}
+            _ => return false,
+        }
+        page = match page.checked_add(4096) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    true
+}
+
+/// Copies from `addr` into `buf`, stopping at the first page that isn't
+/// mapped. Returns how many bytes were copied.
+pub fn read_bytes(addr: VirtAddr, buf: &mut [u8]) -> usize {
+    let mut done = 0;
+    while done < buf.len() {
+        let start = match VirtAddr::try_new(addr.as_u64().wrapping_add(done as u64)) {
+            Ok(start) if is_mapped(start) => start,
+            _ => break,
+        };
+        let page_end = start.align_down(4096u64).as_u64() + 4096;
+        let n = ((page_end - start.as_u64()) as usize).min(buf.len() - done);
+        unsafe {
+            core::ptr::copy_nonoverlapping(start.as_ptr::<u8>(), buf[done..].as_mut_ptr(), n);
+        }
+        done += n;
+    }
+    done
+}
+
+/// Removes the mapping of `page` and makes sure no CPU keeps using it.
+/// Returns the frame it pointed to, freeing that is up to the caller.
+pub fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
+    let result = with_mapper(|mapper, _| {
+        let (frame, flush) = mapper.unmap(page)?;
+        // the shootdown flushes this CPU too
+        flush.ignore();
+        Ok(frame)
+    });
+    let frame = result.unwrap_or(Err(UnmapError::PageNotMapped))?;
+    // not under the page table locks, a CPU spinning on them with interrupts
+    // off could never acknowledge the IPI
+    crate::tlb::shootdown(page.start_address(), 1);
+    Ok(frame)
+}
+
+/// Changes the flags of a mapped page on every CPU, e.g. to write protect it.
+pub fn update_flags(page: Page, flags: Flags) -> Result<(), FlagUpdateError> {
+    let result = with_mapper(|mapper, _| {
+        let flush = unsafe { mapper.update_flags(page, flags)? };
+        flush.ignore();
+        Ok(())
+    });
+    result.unwrap_or(Err(FlagUpdateError::PageNotMapped))?;
+    // after with_mapper, see `unmap`
+    crate::tlb::shootdown(page.start_address(), 1);
+    Ok(())
+}
+
+/// Makes sure the physical range is reachable through `phys_to_virt` and
+/// returns its virtual address. Pages that the bootloader's physical memory
+/// mapping doesn't cover (it stops at the end of the memory map) are mapped
+/// uncached, which is what device registers want.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let virt = phys_to_virt(phys);
+    with_mapper(|mapper, frame_allocator| {
+        let start = phys.align_down(4096u64).as_u64();
+        for frame_addr in (start..phys.as_u64() + size).step_by(4096) {
+            let frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(frame_addr));
+            let page: Page = Page::containing_address(phys_to_virt(frame.start_address()));
+            if mapper.translate_addr(page.start_address()).is_some() {
+                continue;
+            }
+            let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+            if let Ok(flush) = unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                flush.flush();
+            }
+        }
+    });
+    virt
+}
+
 /// A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
 
@@ -111,8 +314,9 @@ impl BootInfoFrameAllocator {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        // map each region to its address range, leaving low memory alone
+        let addr_ranges = usable_regions
+            .map(|r| r.range.start_addr().max(LOW_MEMORY_END)..r.range.end_addr());
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
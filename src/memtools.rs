@@ -0,0 +1,140 @@
+//! memory inspection for the shell: peek, poke and hexdump
+//!
+//! every access is checked against the page tables first, so a mistyped
+//! address prints an error instead of page faulting. physical addresses are
+//! reached through the bootloader's mapping of all physical memory.
+
+use core::fmt::{self, Write};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use crate::strutils::StrBuf;
+use crate::println;
+
+/// longest dump `hexdump` does in one go
+pub const MAX_HEXDUMP: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// not a valid (canonical) address
+    BadAddress,
+    NotMapped,
+    ReadOnly,
+    /// only 1, 2, 4 and 8 byte accesses
+    BadWidth,
+    /// accesses have to be aligned to their width
+    Unaligned,
+    TooLong,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessError::BadAddress => "not a valid address",
+            AccessError::NotMapped => "not mapped",
+            AccessError::ReadOnly => "mapped read-only",
+            AccessError::BadWidth => "width has to be 1, 2, 4 or 8",
+            AccessError::Unaligned => "address isn't aligned to the width",
+            AccessError::TooLong => "too long",
+        })
+    }
+}
+
+/// the virtual address for the `len` bytes at `addr`, a physical one if
+/// `physical` is set. all of them have to have a virtual address
+pub fn resolve(addr: u64, len: u64, physical: bool) -> Result<VirtAddr, AccessError> {
+    let last = addr.checked_add(len.saturating_sub(1)).ok_or(AccessError::BadAddress)?;
+    if physical {
+        let to_virt = |addr| PhysAddr::try_new(addr).ok().and_then(memory::try_phys_to_virt);
+        to_virt(last).ok_or(AccessError::BadAddress)?;
+        to_virt(addr).ok_or(AccessError::BadAddress)
+    } else {
+        VirtAddr::try_new(addr).map_err(|_| AccessError::BadAddress)
+    }
+}
+
+fn check(addr: VirtAddr, len: u64, write: bool) -> Result<(), AccessError> {
+    if memory::range_accessible(addr, len, write) {
+        Ok(())
+    } else if write && memory::range_accessible(addr, len, false) {
+        Err(AccessError::ReadOnly)
+    } else {
+        Err(AccessError::NotMapped)
+    }
+}
+
+fn check_width(addr: VirtAddr, width: u64) -> Result<(), AccessError> {
+    if !matches!(width, 1 | 2 | 4 | 8) {
+        return Err(AccessError::BadWidth);
+    }
+    if addr.as_u64() % width != 0 {
+        return Err(AccessError::Unaligned);
+    }
+    Ok(())
+}
+
+/// read one value of `width` bytes, as a single access so it's also fine
+/// for device registers
+pub fn peek(addr: VirtAddr, width: u64) -> Result<u64, AccessError> {
+    check_width(addr, width)?;
+    check(addr, width, false)?;
+    let ptr = addr.as_u64();
+    Ok(unsafe {
+        match width {
+            1 => (ptr as *const u8).read_volatile() as u64,
+            2 => (ptr as *const u16).read_volatile() as u64,
+            4 => (ptr as *const u32).read_volatile() as u64,
+            _ => (ptr as *const u64).read_volatile(),
+        }
+    })
+}
+
+/// write one value of `width` bytes, higher bits of `value` are dropped
+pub fn poke(addr: VirtAddr, value: u64, width: u64) -> Result<(), AccessError> {
+    check_width(addr, width)?;
+    check(addr, width, true)?;
+    let ptr = addr.as_u64();
+    unsafe {
+        match width {
+            1 => (ptr as *mut u8).write_volatile(value as u8),
+            2 => (ptr as *mut u16).write_volatile(value as u16),
+            4 => (ptr as *mut u32).write_volatile(value as u32),
+            _ => (ptr as *mut u64).write_volatile(value),
+        }
+    }
+    Ok(())
+}
+
+/// print `len` bytes from `addr` like `hexdump -C`, offsets are relative to `addr`
+pub fn hexdump(addr: VirtAddr, len: u64) -> Result<(), AccessError> {
+    if len > MAX_HEXDUMP {
+        return Err(AccessError::TooLong);
+    }
+    check(addr, len, false)?;
+
+    println!("{:#x}, {} bytes:", addr.as_u64(), len);
+    for offset in (0..len).step_by(16) {
+        let mut bytes = [0u8; 16];
+        let n = (len - offset).min(16) as usize;
+        memory::read_bytes(addr + offset, &mut bytes[..n]);
+
+        let mut line: StrBuf<80> = StrBuf::new();
+        let _ = write!(line, "{:08x} ", offset);
+        for (i, b) in bytes.iter().enumerate() {
+            if i % 8 == 0 {
+                let _ = line.write_char(' ');
+            }
+            if i < n {
+                let _ = write!(line, "{:02x} ", b);
+            } else {
+                let _ = line.write_str("   ");
+            }
+        }
+        let _ = line.write_str(" |");
+        for &b in &bytes[..n] {
+            let _ = line.write_char(if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' });
+        }
+        let _ = line.write_char('|');
+        println!("{}", line.as_str());
+    }
+    Ok(())
+}
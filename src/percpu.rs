@@ -0,0 +1,136 @@
+//! per-CPU data, reached through the GS segment base
+//!
+//! every CPU points IA32_GS_BASE at its own `PerCpu`, whose first field is a
+//! pointer to itself, so `mov reg, gs:[0]` finds it without knowing which CPU
+//! we're on. IA32_KERNEL_GS_BASE gets the same value: everything runs in ring 0
+//! today, and once there is a user mode entry path its `swapgs` will swap in
+//! the right base.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+use crate::smp::MAX_CPUS;
+
+/// counters kept by each CPU about itself
+pub struct CpuStats {
+    pub interrupts: AtomicU64,
+    pub timer_ticks: AtomicU64,
+    pub exceptions: AtomicU64,
+    pub ipis: AtomicU64,
+    pub idle_wakeups: AtomicU64,
+}
+
+#[repr(C)]
+pub struct PerCpu {
+    /// has to stay the first field, see `current`
+    self_ptr: AtomicU64,
+    cpu_id: AtomicUsize,
+    apic_id: AtomicU8,
+    /// id of the task running on this CPU, 0 is the kernel itself (shell and idle loop)
+    current_task: AtomicUsize,
+    /// how many interrupt handlers deep we are
+    irq_depth: AtomicUsize,
+    pub stats: CpuStats,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const PERCPU_INIT: PerCpu = PerCpu {
+    self_ptr: AtomicU64::new(0),
+    cpu_id: AtomicUsize::new(0),
+    apic_id: AtomicU8::new(0),
+    current_task: AtomicUsize::new(0),
+    irq_depth: AtomicUsize::new(0),
+    stats: CpuStats {
+        interrupts: AtomicU64::new(0),
+        timer_ticks: AtomicU64::new(0),
+        exceptions: AtomicU64::new(0),
+        ipis: AtomicU64::new(0),
+        idle_wakeups: AtomicU64::new(0),
+    },
+};
+
+static PERCPU: [PerCpu; MAX_CPUS] = [PERCPU_INIT; MAX_CPUS];
+
+/// GS base is 0 until the boot CPU ran `init`, `current` can't use it before that
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// point this CPU's GS base at its `PerCpu`. every CPU calls this once, before
+/// it enables interrupts
+pub fn init(cpu: usize, apic_id: u8) {
+    let area = &PERCPU[cpu];
+    let addr = area as *const PerCpu as u64;
+    area.self_ptr.store(addr, Ordering::SeqCst);
+    area.cpu_id.store(cpu, Ordering::SeqCst);
+    area.apic_id.store(apic_id, Ordering::SeqCst);
+
+    GsBase::write(VirtAddr::new(addr));
+    KernelGsBase::write(VirtAddr::new(addr));
+    READY.store(true, Ordering::SeqCst);
+}
+
+/// the data of the CPU we're running on
+pub fn current() -> &'static PerCpu {
+    if !READY.load(Ordering::Relaxed) {
+        // early boot, only the boot CPU is running
+        return &PERCPU[0];
+    }
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+/// the data of any CPU, for statistics
+pub fn get(cpu: usize) -> &'static PerCpu {
+    &PERCPU[cpu]
+}
+
+impl PerCpu {
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn current_task(&self) -> usize {
+        self.current_task.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_task(&self, task: usize) {
+        self.current_task.store(task, Ordering::Relaxed);
+    }
+
+    pub fn irq_depth(&self) -> usize {
+        self.irq_depth.load(Ordering::Relaxed)
+    }
+
+    /// for when the interrupted handlers are abandoned instead of returning
+    pub fn reset_irq_depth(&self) {
+        self.irq_depth.store(0, Ordering::Relaxed);
+    }
+}
+
+/// number of the CPU we're running on
+pub fn cpu_id() -> usize {
+    current().cpu_id()
+}
+
+/// called at the start of every interrupt handler
+pub fn irq_enter() {
+    let cpu = current();
+    cpu.irq_depth.fetch_add(1, Ordering::Relaxed);
+    cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+}
+
+/// called at the end of every interrupt handler that called `irq_enter`
+pub fn irq_exit() {
+    current().irq_depth.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// true while an interrupt handler is running on this CPU
+pub fn in_interrupt() -> bool {
+    current().irq_depth() > 0
+}
@@ -0,0 +1,230 @@
+//! power off and reboot
+//! https://wiki.osdev.org/Shutdown
+//! https://wiki.osdev.org/Reboot
+//!
+//! both try the ACPI way first and then fall back to older / emulator
+//! specific mechanisms. neither of them returns.
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::{self, Fadt, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use crate::memory::phys_to_virt;
+use crate::{println, println_colored, hlt_loop};
+use crate::vga_buffer::WARNING_COLOR;
+
+/// PM1 control register bits
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+
+/// AML opcodes needed to find the \_S5 package in the DSDT
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_CMD_PULSE_RESET: u8 = 0xfe;
+
+/// port of QEMU's `-device isa-debug-exit,iobase=0xf4,iosize=0x04`
+const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// exit codes for the isa-debug-exit device. QEMU exits with `(code << 1) | 1`
+/// so none of these can be confused with QEMU's own exit codes 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// ~1us per write, port 0x80 is the POST code port nobody listens to
+fn io_delay(us: u32) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
+
+/// terminate QEMU if it was started with the isa-debug-exit device,
+/// does nothing (and returns) everywhere else
+pub fn exit_qemu(code: QemuExitCode) {
+    let mut port: Port<u32> = Port::new(QEMU_DEBUG_EXIT_PORT);
+    unsafe { port.write(code as u32) };
+}
+
+/// find SLP_TYPa and SLP_TYPb in the \_S5 object of the DSDT.
+///
+/// this is not an AML interpreter, it only understands the
+/// `Name(_S5, Package() { a, b, ... })` form every firmware uses
+fn find_s5(fadt: &Fadt) -> Option<(u16, u16)> {
+    if fadt.dsdt == 0 {
+        return None;
+    }
+    let dsdt = phys_to_virt(PhysAddr::new(fadt.dsdt)).as_ptr::<u8>();
+    let bytes = unsafe {
+        let len = core::ptr::read_unaligned(dsdt.add(4) as *const u32) as usize;
+        core::slice::from_raw_parts(dsdt, len)
+    };
+
+    let mut i = 36; // skip the table header
+    while i + 4 < bytes.len() {
+        if &bytes[i..i + 4] != b"_S5_" {
+            i += 1;
+            continue;
+        }
+        // has to be the name of a NameOp, optionally rooted: `08 [5c] _S5_`
+        let named = (i >= 1 && bytes[i - 1] == AML_NAME_OP)
+            || (i >= 2 && bytes[i - 2] == AML_NAME_OP && bytes[i - 1] == AML_ROOT_CHAR);
+        if !named || bytes.get(i + 4) != Some(&AML_PACKAGE_OP) {
+            i += 1;
+            continue;
+        }
+
+        // PkgLength: top two bits of the lead byte count the extra bytes
+        let mut p = i + 5;
+        p += ((*bytes.get(p)? >> 6) & 3) as usize + 1;
+        p += 1; // NumElements
+
+        let mut element = || -> Option<u16> {
+            let mut v = *bytes.get(p)?;
+            if v == AML_BYTE_PREFIX {
+                p += 1;
+                v = *bytes.get(p)?;
+            }
+            p += 1;
+            Some(v as u16)
+        };
+        let slp_typ_a = element()?;
+        let slp_typ_b = element().unwrap_or(0);
+        return Some((slp_typ_a, slp_typ_b));
+    }
+    None
+}
+
+/// switch to ACPI mode so PM1 writes are honoured, if the firmware isn't there yet
+fn enable_acpi(fadt: &Fadt) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a.read() } & SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    let mut smi: Port<u8> = Port::new(fadt.smi_command as u16);
+    unsafe { smi.write(fadt.acpi_enable) };
+    // give the firmware up to ~300ms
+    for _ in 0..300 {
+        if unsafe { pm1a.read() } & SCI_EN != 0 {
+            return;
+        }
+        io_delay(1000);
+    }
+}
+
+/// enter the S5 (soft off) sleep state, returns if that didn't work
+fn acpi_poweroff() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) if fadt.pm1a_control_block != 0 => fadt,
+        _ => return,
+    };
+    let (slp_typ_a, slp_typ_b) = match find_s5(&fadt) {
+        Some(s5) => s5,
+        None => {
+            println_colored!(WARNING_COLOR, "ACPI: no \\_S5 object in the DSDT");
+            return;
+        }
+    };
+
+    enable_acpi(&fadt);
+
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    unsafe { pm1a.write((slp_typ_a << SLP_TYP_SHIFT) | SLP_EN) };
+    if fadt.pm1b_control_block != 0 {
+        let mut pm1b: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+        unsafe { pm1b.write((slp_typ_b << SLP_TYP_SHIFT) | SLP_EN) };
+    }
+    io_delay(100_000);
+}
+
+/// turn the machine off
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    acpi_poweroff();
+
+    // emulator specific shortcuts: QEMU >= 2.0, Bochs and old QEMU, VirtualBox
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xb004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+    io_delay(100_000);
+
+    exit_qemu(QemuExitCode::Success);
+
+    println!("shutdown failed, it is now safe to turn off your computer");
+    hlt_loop();
+}
+
+/// poke the FADT reset register, returns if there is none or it didn't work
+fn acpi_reset() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    let reg = match fadt.reset_reg {
+        Some(reg) => reg,
+        None => return,
+    };
+    match reg.address_space {
+        ADDRESS_SPACE_IO => unsafe {
+            Port::<u8>::new(reg.address as u16).write(fadt.reset_value);
+        },
+        ADDRESS_SPACE_MEMORY => unsafe {
+            let ptr = phys_to_virt(PhysAddr::new(reg.address)).as_mut_ptr::<u8>();
+            ptr.write_volatile(fadt.reset_value);
+        },
+        // PCI configuration space resets aren't supported
+        _ => return,
+    }
+    io_delay(100_000);
+}
+
+/// pulse the CPU reset line through the 8042 keyboard controller
+fn kbc_reset() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    unsafe {
+        for _ in 0..10_000 {
+            if status.read() & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            io_delay(10);
+        }
+        command.write(KBC_CMD_PULSE_RESET);
+    }
+    io_delay(100_000);
+}
+
+/// load an empty IDT and raise an exception, nothing can handle it so the
+/// CPU triple faults and resets
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop();
+}
+
+/// restart the machine
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    acpi_reset();
+    kbc_reset();
+    triple_fault();
+}
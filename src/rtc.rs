@@ -0,0 +1,363 @@
+//! CMOS real-time clock driver
+//! https://wiki.osdev.org/CMOS
+//!
+//! the RTC is assumed to run in UTC. we read it once at boot and then combine
+//! it with the monotonic PIT clock (`time::uptime_ms`) so reading the time
+//! doesn't have to go through the slow CMOS ports every time.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::time;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// bit 7 of the address port masks NMIs while a register is selected
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+/// not standardised, but where almost every BIOS keeps the century. the
+/// FADT says where it really is, see `century_register`
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24H: u8 = 1 << 1;
+/// in 12 hour mode the top bit of the hours register means PM
+const HOUR_PM: u8 = 1 << 7;
+
+/// UNIX time of the RTC reading taken at `BOOT_MS` on the monotonic clock
+static BOOT_UNIX: AtomicU64 = AtomicU64::new(0);
+static BOOT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// number of periodic interrupts received on IRQ8
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// a calendar date and time, always UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        (days * 86400) as u64
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(secs: u64) -> DateTime {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem % 3600 / 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// sanity check before writing a date back into the CMOS
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970 && self.year < 2200
+            && self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn is_leap(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// days since the UNIX epoch for a proleptic gregorian date
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// inverse of `days_from_civil`, returns (year, month, day)
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(reg);
+        data.read()
+    }
+}
+
+/// writes go with NMIs masked so nothing can leave the CMOS half programmed
+fn write_register(reg: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(reg | NMI_DISABLE);
+        data.write(value);
+        address.write(0); // unmask NMIs again
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(v: u8) -> u8 {
+    (v & 0x0f) + (v >> 4) * 10
+}
+
+fn binary_to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+/// raw register values, in whatever format the RTC is configured for
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// the century's CMOS register, from the FADT if it has one
+fn century_register() -> u8 {
+    crate::acpi::fadt().map(|fadt| fadt.century).filter(|&reg| reg != 0).unwrap_or(REG_CENTURY)
+}
+
+fn read_raw() -> RawTime {
+    let century = century_register();
+    while update_in_progress() {}
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: read_register(century),
+    }
+}
+
+/// read the RTC directly, converting from BCD and 12 hour mode if needed
+pub fn read_rtc() -> DateTime {
+    // an update can start right after we checked the UIP flag, so keep
+    // reading until two reads in a row agree
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = raw.hour & !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        raw.second = bcd_to_binary(raw.second);
+        raw.minute = bcd_to_binary(raw.minute);
+        hour = bcd_to_binary(hour);
+        raw.day = bcd_to_binary(raw.day);
+        raw.month = bcd_to_binary(raw.month);
+        raw.year = bcd_to_binary(raw.year);
+        raw.century = bcd_to_binary(raw.century);
+    }
+
+    if status_b & STATUS_B_24H == 0 {
+        // 12 hour clock: 12am is midnight, 12pm is noon
+        if pm && hour != 12 {
+            hour += 12;
+        } else if !pm && hour == 12 {
+            hour = 0;
+        }
+    }
+
+    // only trust the century register if it holds something plausible
+    let year = if raw.century >= 19 && raw.century <= 21 {
+        raw.century as u16 * 100 + raw.year as u16
+    } else if raw.year < 70 {
+        2000 + raw.year as u16
+    } else {
+        1900 + raw.year as u16
+    };
+
+    DateTime {
+        year,
+        month: raw.month,
+        day: raw.day,
+        hour,
+        minute: raw.minute,
+        second: raw.second,
+    }
+}
+
+/// program the RTC with a new date, keeping its BCD/binary and 12/24h format
+pub fn write_rtc(dt: &DateTime) {
+    let status_b = read_register(REG_STATUS_B);
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let conv = |v: u8| if binary { v } else { binary_to_bcd(v) };
+
+    let hour = if status_b & STATUS_B_24H == 0 {
+        let pm = dt.hour >= 12;
+        let h12 = match dt.hour % 12 { 0 => 12, h => h };
+        conv(h12) | if pm { HOUR_PM } else { 0 }
+    } else {
+        conv(dt.hour)
+    };
+
+    let century = century_register();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // SET stops the update cycle while we write
+        write_register(REG_STATUS_B, status_b | STATUS_B_SET);
+        write_register(REG_SECONDS, conv(dt.second));
+        write_register(REG_MINUTES, conv(dt.minute));
+        write_register(REG_HOURS, hour);
+        write_register(REG_DAY, conv(dt.day));
+        write_register(REG_MONTH, conv(dt.month));
+        write_register(REG_YEAR, conv((dt.year % 100) as u8));
+        write_register(century, conv((dt.year / 100) as u8));
+        write_register(REG_STATUS_B, status_b & !STATUS_B_SET);
+    });
+}
+
+/// latch the RTC against the monotonic clock, called from `init()`
+pub fn init() {
+    sync();
+}
+
+/// re-read the RTC and make it the base for `unix_time()`
+pub fn sync() {
+    let now = read_rtc().to_unix();
+    BOOT_UNIX.store(now, Ordering::Relaxed);
+    BOOT_MS.store(time::uptime_ms(), Ordering::Relaxed);
+}
+
+/// set the wall clock, both in the CMOS and for `unix_time()`
+pub fn set_time(dt: &DateTime) {
+    write_rtc(dt);
+    BOOT_UNIX.store(dt.to_unix(), Ordering::Relaxed);
+    BOOT_MS.store(time::uptime_ms(), Ordering::Relaxed);
+}
+
+/// milliseconds since the UNIX epoch
+pub fn unix_time_ms() -> u64 {
+    let base = BOOT_UNIX.load(Ordering::Relaxed) * 1000;
+    base + time::uptime_ms() - BOOT_MS.load(Ordering::Relaxed)
+}
+
+/// seconds since the UNIX epoch
+pub fn unix_time() -> u64 {
+    unix_time_ms() / 1000
+}
+
+/// current wall clock time
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+/// enable the periodic interrupt on IRQ8 at 32768 >> (rate - 1) Hz.
+/// rate has to be between 3 (8192Hz) and 15 (2Hz)
+pub fn enable_periodic_interrupt(rate: u8) -> bool {
+    if !(3..=15).contains(&rate) {
+        return false;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // throw away anything pending so the next interrupt is raised
+        read_register(REG_STATUS_C);
+        crate::interrupts::unmask_irq(8);
+    });
+    true
+}
+
+pub fn disable_periodic_interrupt() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+        crate::interrupts::mask_irq(8);
+    });
+}
+
+/// frequency of the periodic interrupt in Hz, 0 if it is disabled
+pub fn periodic_frequency() -> u32 {
+    if read_register(REG_STATUS_B) & STATUS_B_PERIODIC == 0 {
+        return 0;
+    }
+    match read_register(REG_STATUS_A) & 0x0f {
+        0 => 0,
+        rate => 32768 >> (rate - 1),
+    }
+}
+
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// called from the IRQ8 handler. register C has to be read or the RTC
+/// won't raise another interrupt
+pub fn handle_interrupt() {
+    read_register(REG_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
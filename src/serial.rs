@@ -0,0 +1,418 @@
+//! 16550 UARTs on COM1 to COM4
+//! https://wiki.osdev.org/Serial_Ports
+//!
+//! 8N1 at any baud rate the 115200 base divides evenly, with FIFOs. what a
+//! port receives with its interrupt on (IRQ4 for COM1/COM3, IRQ3 for
+//! COM2/COM4) goes into a small ring until someone reads it. the handler
+//! doesn't take the port's lock, the UART has to be emptied every time or
+//! the edge triggered PIC never sees its IRQ again. COM1 comes up
+//! that way at boot and is the serial console: everything `print!` shows is
+//! written to it too, for QEMU's `-serial stdio` and CI logs.
+//!
+//! the GDB stub polls COM1 with interrupts disabled, its `init` turns the
+//! receive interrupt off again. while the stub is active the console copy
+//! doesn't go to COM1, it would get in the middle of the protocol.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use crate::lock::Mutex;
+use crate::{gdbstub, interrupts};
+
+pub const COM1_BASE: u16 = 0x3f8;
+pub const COM2_BASE: u16 = 0x2f8;
+pub const COM3_BASE: u16 = 0x3e8;
+pub const COM4_BASE: u16 = 0x2e8;
+
+/// the IRQ lines, each shared by two ports
+pub const COM1_COM3_IRQ: u8 = 4;
+pub const COM2_COM4_IRQ: u8 = 3;
+
+// register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// with DLAB set, DATA and INTERRUPT_ENABLE are the divisor latch instead
+const FIFO_CONTROL: u16 = 2;
+/// the same port as FIFO_CONTROL, when read
+const INTERRUPT_ID: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+/// no function, just a byte to read back; the 8250 doesn't have it
+const SCRATCH: u16 = 7;
+
+const LINE_8N1: u8 = 0b11;
+const LINE_DLAB: u8 = 1 << 7;
+/// enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE_CLEAR_14: u8 = 0xc7;
+/// both bits set when the FIFOs are on and work, only on a 16550A and later
+const ID_FIFO_ENABLED: u8 = 0xc0;
+const ID_NONE_PENDING: u8 = 1 << 0;
+/// in INTERRUPT_ID: the reason for the interrupt, highest priority first
+const ID_REASON: u8 = 0x0e;
+const ID_LINE_STATUS: u8 = 0x06;
+const ID_RECEIVED_DATA: u8 = 0x04;
+const ID_RECEIVE_TIMEOUT: u8 = 0x0c;
+const ID_MODEM_STATUS: u8 = 0x00;
+/// reasons one interrupt handler call handles, a UART that keeps finding
+/// new ones is broken
+const MAX_REASONS: usize = 16;
+const ENABLE_RECEIVED_DATA: u8 = 1 << 0;
+/// DTR, RTS and OUT2 (which gates the IRQ line on PCs)
+const MODEM_DTR_RTS_OUT2: u8 = 0x0b;
+const STATUS_DATA_READY: u8 = 1 << 0;
+const STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// the UART clock divided by 16
+const BASE_BAUD: u32 = 115200;
+pub const DEFAULT_BAUD: u32 = 115200;
+
+/// received bytes kept per port until they're read, more are dropped
+const RX_BUFFER: usize = 256;
+
+fn inb(port: u16) -> u8 {
+    unsafe { Port::<u8>::new(port).read() }
+}
+
+/// the IRQ line a port is wired to on PCs
+const fn irq_of(base: u16) -> u8 {
+    match base {
+        COM1_BASE | COM3_BASE => COM1_COM3_IRQ,
+        _ => COM2_COM4_IRQ,
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicU8 = AtomicU8::new(0);
+
+/// The receive side of a port. The interrupt handler fills the ring without
+/// taking any lock, everyone else only takes bytes out of it.
+struct Receiver {
+    base: u16,
+    enabled: AtomicBool,
+    buf: [AtomicU8; RX_BUFFER],
+    /// bytes ever put in and taken out, the ring positions are these modulo
+    /// RX_BUFFER
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// received while the ring was full
+    dropped: AtomicUsize,
+}
+
+impl Receiver {
+    const fn new(base: u16) -> Receiver {
+        Receiver {
+            base,
+            enabled: AtomicBool::new(false),
+            buf: [EMPTY; RX_BUFFER],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// move what the UART has into the ring. only ever runs on the boot CPU,
+    /// which has the legacy IRQs, so there's one of these at a time
+    fn drain(&self) {
+        while inb(self.base + LINE_STATUS) & STATUS_DATA_READY != 0 {
+            let byte = inb(self.base + DATA);
+            let head = self.head.load(Ordering::Relaxed);
+            if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= RX_BUFFER {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            self.buf[head % RX_BUFFER].store(byte, Ordering::Relaxed);
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    /// deal with everything the UART wants, reading the interrupt ID
+    /// acknowledges some of it and the rest is acknowledged by reading the
+    /// register it's about
+    fn service(&self) {
+        for _ in 0..MAX_REASONS {
+            let id = inb(self.base + INTERRUPT_ID);
+            if id & ID_NONE_PENDING != 0 {
+                return;
+            }
+            match id & ID_REASON {
+                ID_LINE_STATUS => {
+                    inb(self.base + LINE_STATUS);
+                }
+                ID_RECEIVED_DATA | ID_RECEIVE_TIMEOUT => self.drain(),
+                ID_MODEM_STATUS => {
+                    inb(self.base + MODEM_STATUS);
+                }
+                // transmitter empty, acknowledged by the ID read
+                _ => {}
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<u8> {
+        loop {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail == self.head.load(Ordering::Acquire) {
+                return None;
+            }
+            let byte = self.buf[tail % RX_BUFFER].load(Ordering::Relaxed);
+            let next = tail.wrapping_add(1);
+            if self.tail.compare_exchange(tail, next, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                return Some(byte);
+            }
+        }
+    }
+}
+
+static RECEIVERS: [Receiver; 4] = [
+    Receiver::new(COM1_BASE),
+    Receiver::new(COM2_BASE),
+    Receiver::new(COM3_BASE),
+    Receiver::new(COM4_BASE),
+];
+
+pub struct SerialPort {
+    base: u16,
+    baud: u32,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base, baud: DEFAULT_BAUD }
+    }
+
+    fn receiver(&self) -> &'static Receiver {
+        match self.base {
+            COM1_BASE => &RECEIVERS[0],
+            COM2_BASE => &RECEIVERS[1],
+            COM3_BASE => &RECEIVERS[2],
+            _ => &RECEIVERS[3],
+        }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) }
+    }
+
+    /// 115200 baud 8N1 with FIFOs, no interrupts
+    pub fn init(&mut self) {
+        self.configure(DEFAULT_BAUD);
+    }
+
+    /// `baud` 8N1 with FIFOs, no interrupts. false if the base rate can't be
+    /// divided down to it by a 16 bit divisor, the port is left alone then
+    pub fn configure(&mut self, baud: u32) -> bool {
+        if baud == 0 || BASE_BAUD % baud != 0 {
+            return false;
+        }
+        let divisor = match u16::try_from(BASE_BAUD / baud) {
+            Ok(divisor) => divisor,
+            Err(_) => return false,
+        };
+        self.receiver().enabled.store(false, Ordering::SeqCst);
+        self.write_reg(INTERRUPT_ENABLE, 0);
+        self.write_reg(LINE_CONTROL, LINE_DLAB);
+        self.write_reg(DATA, divisor as u8);
+        self.write_reg(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write_reg(LINE_CONTROL, LINE_8N1);
+        self.write_reg(FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.write_reg(MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        self.baud = baud;
+        true
+    }
+
+    /// whether there is a UART at all, by writing the scratch register.
+    /// nothing there reads back 0xff
+    pub fn is_present(&mut self) -> bool {
+        self.write_reg(SCRATCH, 0x5a);
+        self.read_reg(SCRATCH) == 0x5a
+    }
+
+    /// whether the FIFOs are working, they're broken or missing before the 16550A
+    pub fn has_fifo(&self) -> bool {
+        self.read_reg(INTERRUPT_ID) & ID_FIFO_ENABLED == ID_FIFO_ENABLED
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// the IRQ line the port is wired to on PCs
+    pub fn irq(&self) -> u8 {
+        irq_of(self.base)
+    }
+
+    pub fn receive_interrupt(&self) -> bool {
+        self.receiver().enabled.load(Ordering::Relaxed)
+    }
+
+    /// bytes received while the ring was full
+    pub fn dropped(&self) -> usize {
+        self.receiver().dropped.load(Ordering::Relaxed)
+    }
+
+    /// Received bytes go into the ring from now on, on the port's IRQ.
+    /// Unmasking it is up to the caller, see `serial::enable_receive`.
+    pub fn enable_receive_interrupt(&mut self) {
+        let receiver = self.receiver();
+        // anything already waiting would never raise the edge the PIC needs
+        receiver.drain();
+        receiver.enabled.store(true, Ordering::SeqCst);
+        self.write_reg(INTERRUPT_ENABLE, ENABLE_RECEIVED_DATA);
+    }
+
+    /// the oldest byte the receive interrupt put into the ring
+    pub fn read_buffered(&mut self) -> Option<u8> {
+        self.receiver().pop()
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        while self.read_reg(LINE_STATUS) & STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(DATA, byte);
+    }
+
+    /// the next received byte, if there is one, straight from the UART
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.read_reg(LINE_STATUS) & STATUS_DATA_READY != 0 {
+            Some(self.read_reg(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// wait for the next byte
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// text for a terminal: newlines become CR LF
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+pub static COM1: Mutex<SerialPort> = Mutex::new("COM1", SerialPort::new(COM1_BASE));
+pub static COM2: Mutex<SerialPort> = Mutex::new("COM2", SerialPort::new(COM2_BASE));
+pub static COM3: Mutex<SerialPort> = Mutex::new("COM3", SerialPort::new(COM3_BASE));
+pub static COM4: Mutex<SerialPort> = Mutex::new("COM4", SerialPort::new(COM4_BASE));
+
+static PORTS: [&Mutex<SerialPort>; 4] = [&COM1, &COM2, &COM3, &COM4];
+
+/// the COMn `print!` is copied to, 0 for none
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+/// COM1 to COM4 by their number
+pub fn port(n: usize) -> Option<&'static Mutex<SerialPort>> {
+    PORTS.get(n.checked_sub(1)?).copied()
+}
+
+/// Turns on `n`'s receive interrupt and lets its IRQ through.
+pub fn enable_receive(n: usize) {
+    if let Some(port) = port(n) {
+        let mut port = port.lock();
+        port.enable_receive_interrupt();
+        interrupts::unmask_irq(port.irq());
+    }
+}
+
+/// Sets up COM1 as the serial console, receiving on IRQ4. Without a UART
+/// there is no serial console.
+pub fn init() {
+    if !COM1.lock().is_present() {
+        set_console(None);
+        return;
+    }
+    COM1.lock().init();
+    enable_receive(1);
+    set_console(Some(1));
+}
+
+/// The COMn `print!` output is copied to, if any.
+pub fn console() -> Option<usize> {
+    match CONSOLE.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+/// Copies `print!` to COMn from now on, or stops with None.
+pub fn set_console(n: Option<usize>) {
+    CONSOLE.store(n.filter(|&n| port(n).is_some()).unwrap_or(0), Ordering::Relaxed);
+}
+
+/// the receive interrupt for IRQ3 or IRQ4, services the UARTs on it that
+/// have theirs on. no port locks, whoever holds one may be waiting for us
+pub fn handle_interrupt(irq: u8) {
+    for receiver in RECEIVERS.iter() {
+        if irq_of(receiver.base) == irq && receiver.enabled.load(Ordering::SeqCst) {
+            receiver.service();
+        }
+    }
+}
+
+/// whether someone holds the serial console's port
+pub fn console_is_locked() -> bool {
+    console().and_then(port).map_or(false, |port| port.is_locked())
+}
+
+/// the ports may be held by the code that faulted, see `exceptions::unjam_console`
+pub fn unjam() {
+    'ports: for port in PORTS.iter() {
+        for _ in 0..1_000_000 {
+            if port.try_lock().is_some() {
+                continue 'ports;
+            }
+            core::hint::spin_loop();
+        }
+        unsafe { port.force_unlock() };
+    }
+}
+
+/// Writes to the serial console without its lock, for `vga_buffer::set_emergency`.
+/// Another CPU's output may get mixed in, but that's only on the wire.
+pub fn emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let base = match console() {
+        Some(1) if gdbstub::is_active() => return,
+        Some(n) => RECEIVERS[n - 1].base,
+        None => return,
+    };
+    let _ = SerialPort::new(base).write_fmt(args);
+}
+
+/// Writes `print!` output to the serial console, called by `vga_buffer::_print`.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let n = match console() {
+        Some(1) if gdbstub::is_active() => return,
+        Some(n) => n,
+        None => return,
+    };
+    if let Some(port) = port(n) {
+        port.lock().write_fmt(args).unwrap();
+    }
+}
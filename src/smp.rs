@@ -0,0 +1,346 @@
+//! application processor (AP) start-up
+//! https://wiki.osdev.org/Symmetric_Multiprocessing
+//!
+//! the boot CPU copies a small real mode trampoline to `TRAMPOLINE_ADDR`, then
+//! sends every enabled CPU from the MADT an INIT-SIPI-SIPI sequence. the
+//! trampoline switches straight from real mode to long mode using the boot
+//! CPU's page table and calls `ap_entry` on a stack from `AP_STACKS`.
+//! APs are started one at a time since they share the trampoline.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::structures::paging::{PhysFrame, PageTableFlags as Flags};
+use x86_64::PhysAddr;
+use crate::{acpi, apic, debugreg, gdt, interrupts, memory, percpu, time, watchdog, println, println_colored};
+use crate::vga_buffer::WARNING_COLOR;
+
+/// upper bound for everything that is allocated per CPU
+pub const MAX_CPUS: usize = 16;
+
+/// has to match the 0x8000 hardcoded in the trampoline below, and has to be
+/// page aligned and below 1MiB since the SIPI vector is a real mode page number
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+/// CR4.PCIDE can only be set once long mode is active
+const CR4_PCIDE: u64 = 1 << 17;
+
+global_asm!(r#"
+.pushsection .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_cr0
+.global ap_cr3
+.global ap_cr4
+.global ap_stack_top
+.global ap_entry_point
+.global ap_cpu_index
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+
+    lgdtl ap_gdt_ptr - ap_trampoline_start + 0x8000
+
+    # PAE and friends, then the boot CPU's page table
+    movl ap_cr4 - ap_trampoline_start + 0x8000, %eax
+    movl %eax, %cr4
+    movl ap_cr3 - ap_trampoline_start + 0x8000, %eax
+    movl %eax, %cr3
+
+    # EFER.LME | EFER.NXE, the kernel's page tables use the NX bit
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $0x900, %eax
+    wrmsr
+
+    # protected mode and paging in one go puts us in long mode
+    movl ap_cr0 - ap_trampoline_start + 0x8000, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_long_mode - ap_trampoline_start + 0x8000)
+
+.code64
+ap_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorw %ax, %ax
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq ap_stack_top - ap_trampoline_start + 0x8000, %rsp
+    movq ap_cpu_index - ap_trampoline_start + 0x8000, %rdi
+    movq ap_entry_point - ap_trampoline_start + 0x8000, %rax
+    callq *%rax
+2:
+    hlt
+    jmp 2b
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff    # 64 bit code
+    .quad 0x00cf92000000ffff    # data
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + 0x8000
+
+# filled in by start_ap() in the copy at TRAMPOLINE_ADDR
+.balign 8
+ap_cr0:         .quad 0
+ap_cr3:         .quad 0
+ap_cr4:         .quad 0
+ap_stack_top:   .quad 0
+ap_entry_point: .quad 0
+ap_cpu_index:   .quad 0
+ap_trampoline_end:
+.popsection
+"#, options(att_syntax));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_cr0: u8;
+    static ap_cr3: u8;
+    static ap_cr4: u8;
+    static ap_stack_top: u8;
+    static ap_entry_point: u8;
+    static ap_cpu_index: u8;
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Stack([u8; AP_STACK_SIZE]);
+
+/// CPU 0 is the boot CPU and runs on the bootloader's stack
+static mut AP_STACKS: [Stack; MAX_CPUS] = [Stack([0; AP_STACK_SIZE]); MAX_CPUS];
+
+/// what we know about each CPU, indexed by our own CPU number (not the APIC id)
+pub struct Cpu {
+    apic_id: AtomicU8,
+    present: AtomicBool,
+    online: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_INIT: Cpu = Cpu {
+    apic_id: AtomicU8::new(0),
+    present: AtomicBool::new(false),
+    online: AtomicBool::new(false),
+};
+
+static CPUS: [Cpu; MAX_CPUS] = [CPU_INIT; MAX_CPUS];
+
+/// number of CPUs that made it to their idle loop, including the boot CPU
+pub fn cpus_online() -> usize {
+    CPUS.iter().filter(|c| c.online.load(Ordering::SeqCst)).count()
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    cpu < MAX_CPUS && CPUS[cpu].online.load(Ordering::SeqCst)
+}
+
+/// the online CPUs other than this one, bit n for CPU n
+pub fn other_online_cpus() -> u64 {
+    let me = percpu::cpu_id();
+    (0..MAX_CPUS)
+        .filter(|&cpu| cpu != me && is_online(cpu))
+        .fold(0u64, |mask, cpu| mask | 1 << cpu)
+}
+
+/// an IPI to each CPU in `cpus`, a mask as from `other_online_cpus`. one
+/// at a time rather than a broadcast, CPUs that never came online have no
+/// IDT to handle it with
+pub fn send_ipi(cpus: u64, vector: u8) {
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpus & (1 << cpu) != 0) {
+        apic::send_ipi(apic::IpiDest::Apic(apic_id(cpu)), vector);
+    }
+}
+
+pub fn apic_id(cpu: usize) -> u8 {
+    CPUS[cpu].apic_id.load(Ordering::SeqCst)
+}
+
+/// our CPU number for an APIC id
+pub fn cpu_for_apic_id(apic_id: u8) -> Option<usize> {
+    (0..MAX_CPUS).find(|&cpu| {
+        CPUS[cpu].present.load(Ordering::SeqCst) && CPUS[cpu].apic_id.load(Ordering::SeqCst) == apic_id
+    })
+}
+
+/// address of a trampoline symbol in the copy at TRAMPOLINE_ADDR
+fn trampoline_var(symbol: &u8) -> *mut u64 {
+    let offset = symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 };
+    memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + offset)).as_mut_ptr()
+}
+
+/// the trampoline page must not hold anything the kernel still needs
+fn trampoline_page_free(memory_map: &MemoryMap) -> bool {
+    memory_map.iter().any(|r| {
+        r.range.start_addr() <= TRAMPOLINE_ADDR
+            && TRAMPOLINE_ADDR + 4096 <= r.range.end_addr()
+            && (r.region_type == MemoryRegionType::Usable || r.region_type == MemoryRegionType::Bootloader)
+    })
+}
+
+/// copy the trampoline to low memory and identity map it, since the AP
+/// is still executing from there when it switches paging on
+fn install_trampoline(memory_map: &MemoryMap) -> bool {
+    if !trampoline_page_free(memory_map) {
+        println_colored!(WARNING_COLOR, "SMP: {:#x} is in use, can't place the AP trampoline", TRAMPOLINE_ADDR);
+        return false;
+    }
+
+    let (start, len) = unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        (start, &ap_trampoline_end as *const u8 as usize - start as usize)
+    };
+    let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(start, dest, len) };
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    if let Err(e) = memory::identity_map(frame, Flags::PRESENT | Flags::WRITABLE) {
+        println_colored!(WARNING_COLOR, "SMP: can't identity map the AP trampoline: {:?}", e);
+        return false;
+    }
+
+    let (p4, _) = Cr3::read();
+    unsafe {
+        trampoline_var(&ap_cr0).write_volatile(Cr0::read_raw());
+        trampoline_var(&ap_cr3).write_volatile(p4.start_address().as_u64());
+        trampoline_var(&ap_cr4).write_volatile(Cr4::read_raw() & !CR4_PCIDE);
+        trampoline_var(&ap_entry_point).write_volatile(ap_entry as *const () as u64);
+    }
+    true
+}
+
+/// spin (not halt, we want to notice quickly) until the AP reports in
+fn wait_online(cpu: usize, ms: u64) -> bool {
+    let end = time::ticks() + time::ms_to_ticks(ms);
+    while time::ticks() < end {
+        if is_online(cpu) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    is_online(cpu)
+}
+
+fn start_ap(cpu: usize, apic_id: u8) -> bool {
+    let stack_top = unsafe { core::ptr::addr_of!(AP_STACKS[cpu]) as u64 } + AP_STACK_SIZE as u64;
+    unsafe {
+        trampoline_var(&ap_stack_top).write_volatile(stack_top);
+        trampoline_var(&ap_cpu_index).write_volatile(cpu as u64);
+    }
+    CPUS[cpu].apic_id.store(apic_id, Ordering::SeqCst);
+    CPUS[cpu].present.store(true, Ordering::SeqCst);
+
+    // INIT, wait 10ms, then up to two SIPIs
+    apic::send_init(apic_id);
+    time::sleep_ms(10);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        if wait_online(cpu, 1) {
+            return true;
+        }
+    }
+    wait_online(cpu, 1000)
+}
+
+/// first Rust code on an AP, called by the trampoline with its CPU number
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    percpu::init(cpu, apic_id(cpu));
+    gdt::init_cpu(cpu);
+    interrupts::init_idt();
+    apic::init_ap();
+    debugreg::load();
+
+    CPUS[cpu].online.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+    idle_loop()
+}
+
+/// what a CPU does when it has nothing to do: wait for the next interrupt
+pub fn idle_loop() -> ! {
+    let stats = &percpu::current().stats;
+    loop {
+        watchdog::set_idle(true);
+        x86_64::instructions::hlt();
+        watchdog::set_idle(false);
+        watchdog::pet();
+        stats.idle_wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// start every enabled CPU listed in the MADT. needs interrupts enabled on
+/// the boot CPU (the timer is used for the start-up delays) and has to run
+/// after `memory::install`
+pub fn init(memory_map: &MemoryMap) {
+    apic::init();
+
+    let bsp_apic_id = apic::id();
+    percpu::init(0, bsp_apic_id);
+    CPUS[0].apic_id.store(bsp_apic_id, Ordering::SeqCst);
+    CPUS[0].present.store(true, Ordering::SeqCst);
+    CPUS[0].online.store(true, Ordering::SeqCst);
+
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            println_colored!(WARNING_COLOR, "SMP: no MADT, only using the boot CPU");
+            return;
+        }
+    };
+
+    let (p4, _) = Cr3::read();
+    if p4.start_address().as_u64() > u32::MAX as u64 {
+        println_colored!(WARNING_COLOR, "SMP: page table above 4GiB, APs can't load it from real mode");
+        return;
+    }
+    if !install_trampoline(memory_map) {
+        return;
+    }
+
+    let mut cpu = 1;
+    for lapic in madt.local_apics() {
+        if !lapic.enabled() || lapic.apic_id == bsp_apic_id {
+            continue;
+        }
+        if cpu >= MAX_CPUS {
+            println_colored!(WARNING_COLOR, "SMP: more than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+        if !start_ap(cpu, lapic.apic_id) {
+            println_colored!(WARNING_COLOR, "SMP: CPU with APIC id {} didn't come online", lapic.apic_id);
+        }
+        cpu += 1;
+    }
+}
+
+/// the `cpus` shell command
+pub fn print_cpus() {
+    for (cpu, state) in CPUS.iter().enumerate() {
+        if !state.present.load(Ordering::SeqCst) {
+            continue;
+        }
+        println!("CPU {}: APIC id {} {}{}", cpu, apic_id(cpu),
+            if is_online(cpu) { "online" } else { "offline" },
+            if cpu == 0 { " (boot CPU)" } else { "" });
+        let stats = &percpu::get(cpu).stats;
+        println!("    irqs {} timer {} exceptions {} ipis {} idle wakeups {}",
+            stats.interrupts.load(Ordering::Relaxed),
+            stats.timer_ticks.load(Ordering::Relaxed),
+            stats.exceptions.load(Ordering::Relaxed),
+            stats.ipis.load(Ordering::Relaxed),
+            stats.idle_wakeups.load(Ordering::Relaxed));
+    }
+    println!("{} CPU(s) online", cpus_online());
+}
@@ -1,10 +1,11 @@
-use crate::vga_buffer::{BUFFER_WIDTH};
-
 /// compare 2 char arrays bounded by n, 
 /// returns true if they are identical
-pub fn strcmpl(s1: &[char; BUFFER_WIDTH], s2: &str, n: usize) -> bool {
+pub fn strcmpl(s1: &[char], s2: &str, n: usize) -> bool {
 
 	let s2_b = s2.as_bytes();
+	if s1.len() < n || s2_b.len() < n {
+		return false;
+	}
 	// println!("strcmpl: {:?} vs {:?}", s1, s2_b);
 	for i in 0..n {
 		if s1[i] != s2_b[i] as char {
@@ -13,4 +14,66 @@ pub fn strcmpl(s1: &[char; BUFFER_WIDTH], s2: &str, n: usize) -> bool {
 		}
 	}
 	return true;
-}
\ No newline at end of file
+}
+/// copy a shell line into `buf` as bytes and return it as a trimmed str,
+/// anything that isn't ASCII is replaced by '?'. cut off at the end of `buf`
+pub fn line_to_str<'a>(line: &[char], buf: &'a mut [u8]) -> &'a str {
+	let mut n = 0;
+	for &c in line.iter().take(buf.len()) {
+		if c == '\0' {
+			break;
+		}
+		buf[n] = if c.is_ascii() { c as u8 } else { b'?' };
+		n += 1;
+	}
+	core::str::from_utf8(&buf[..n]).unwrap_or("").trim()
+}
+
+/// parse a decimal or 0x prefixed hex number
+pub fn parse_num(s: &str) -> Option<u64> {
+	if s.starts_with("0x") || s.starts_with("0X") {
+		u64::from_str_radix(&s[2..], 16).ok()
+	} else {
+		s.parse::<u64>().ok()
+	}
+}
+
+/// fixed size text buffer for formatting without a heap,
+/// anything that doesn't fit is cut off
+pub struct StrBuf<const N: usize> {
+	buf: [u8; N],
+	len: usize,
+}
+
+impl<const N: usize> StrBuf<N> {
+	pub const fn new() -> Self {
+		StrBuf { buf: [0; N], len: 0 }
+	}
+
+	pub fn as_str(&self) -> &str {
+		// only ever filled from &str, but a cut can land inside a character
+		match core::str::from_utf8(&self.buf[..self.len]) {
+			Ok(s) => s,
+			Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or(""),
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.len = 0;
+	}
+}
+
+impl<const N: usize> Default for StrBuf<N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<const N: usize> core::fmt::Write for StrBuf<N> {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		let n = s.len().min(N - self.len);
+		self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+		self.len += n;
+		Ok(())
+	}
+}
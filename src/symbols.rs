@@ -0,0 +1,43 @@
+//! the kernel's own symbol table, for backtraces and fault reports
+//!
+//! generated by build.rs from the `nm` output of the previous build, so it is
+//! empty after a plain `cargo build` and only right once build_bootimage.sh
+//! has rebuilt until the table stopped changing.
+
+use core::fmt;
+
+// defines `SYMBOLS`: (address, size, name) of every function, sorted by address
+include!(concat!(env!("OUT_DIR"), "/ksyms.rs"));
+
+/// the function `addr` is in and the offset into it
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let i = match SYMBOLS.binary_search_by_key(&addr, |s| s.0) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let (start, size, name) = SYMBOLS[i];
+    // symbols from assembly have no size, give them the benefit of the doubt
+    if size != 0 && addr >= start + size {
+        return None;
+    }
+    Some((name, addr - start))
+}
+
+/// address of a function by name
+pub fn address_of(name: &str) -> Option<u64> {
+    SYMBOLS.iter().find(|s| s.2 == name).map(|s| s.0)
+}
+
+/// formats as `function+0xoffset`, or just the address if it isn't in the table
+pub struct Symbol(pub u64);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some((name, 0)) => write!(f, "{}", name),
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
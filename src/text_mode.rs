@@ -0,0 +1,256 @@
+//! VGA text modes, programmed through the VGA registers
+//! https://wiki.osdev.org/VGA_Hardware
+//! register values from Chris Giese's public domain modes.c
+//!
+//! the BIOS only sets up 80x25 and it can't be called from long mode, so
+//! switching modes means writing every register by hand and loading the font
+//! into plane 2 ourselves. that also brings text mode back after a Bochs VBE
+//! graphics mode overwrote video memory. the font is the built in one,
+//! squashed to 8 scan lines for the 50 and 60 row modes.
+
+use x86_64::instructions::port::Port;
+use crate::font;
+
+const MISC_WRITE: u16 = 0x3c2;
+const SEQ_INDEX: u16 = 0x3c4;
+const SEQ_DATA: u16 = 0x3c5;
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const GC_INDEX: u16 = 0x3ce;
+const GC_DATA: u16 = 0x3cf;
+/// index and data both go to the same port, reading `INPUT_STATUS` resets
+/// which one the next write is
+const AC_WRITE: u16 = 0x3c0;
+const INPUT_STATUS: u16 = 0x3da;
+/// in the AC index: the palette is set, show the picture again
+const AC_PALETTE_ENABLE: u8 = 0x20;
+
+const SEQ_REGS: usize = 5;
+const CRTC_REGS: usize = 25;
+const GC_REGS: usize = 9;
+const AC_REGS: usize = 21;
+
+// CRTC registers that lock the others, see `program`
+const CRTC_H_BLANK_END: usize = 0x03;
+const CRTC_V_RETRACE_END: usize = 0x11;
+
+// sequencer and graphics controller registers for writing to plane 2
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+const FONT_PLANE: u8 = 2;
+/// every glyph has 32 scan lines of room in plane 2, whatever the font height
+const GLYPH_SLOT: usize = 32;
+
+/// the start of the text buffer, where `GC_MISC` maps video memory in text mode
+const TEXT_WINDOW: usize = 0xb8000;
+
+struct Registers {
+    misc: u8,
+    seq: [u8; SEQ_REGS],
+    crtc: [u8; CRTC_REGS],
+    gc: [u8; GC_REGS],
+    ac: [u8; AC_REGS],
+}
+
+const GC_TEXT: [u8; GC_REGS] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0e, 0x00, 0xff];
+
+/// 16 palette entries, then mode control, overscan, plane enable, panning, color select
+const AC_TEXT: [u8; AC_REGS] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+    0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+    0x0c, 0x00, 0x0f, 0x08, 0x00,
+];
+
+/// 720x400, 9x16 characters
+const REGS_80X25: Registers = Registers {
+    misc: 0x67,
+    seq: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x55, 0x81, 0xbf, 0x1f,
+        0x00, 0x4f, 0x0d, 0x0e, 0x00, 0x00, 0x00, 0x50,
+        0x9c, 0x0e, 0x8f, 0x28, 0x1f, 0x96, 0xb9, 0xa3,
+        0xff,
+    ],
+    gc: GC_TEXT,
+    ac: AC_TEXT,
+};
+
+/// 720x400, 9x8 characters
+const REGS_80X50: Registers = Registers {
+    misc: 0x67,
+    seq: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x55, 0x81, 0xbf, 0x1f,
+        0x00, 0x47, 0x06, 0x07, 0x00, 0x00, 0x01, 0x40,
+        0x9c, 0x8e, 0x8f, 0x28, 0x1f, 0x96, 0xb9, 0xa3,
+        0xff,
+    ],
+    gc: GC_TEXT,
+    ac: AC_TEXT,
+};
+
+/// 720x480, 8x8 characters, with the 28MHz clock
+const REGS_90X60: Registers = Registers {
+    misc: 0xe7,
+    seq: [0x03, 0x01, 0x03, 0x00, 0x02],
+    crtc: [
+        0x6b, 0x59, 0x5a, 0x82, 0x60, 0x8d, 0x0b, 0x3e,
+        0x00, 0x47, 0x06, 0x07, 0x00, 0x00, 0x00, 0x00,
+        0xea, 0x0c, 0xdf, 0x2d, 0x08, 0xe8, 0x05, 0xa3,
+        0xff,
+    ],
+    gc: GC_TEXT,
+    // 8 pixel wide characters don't want the 9 pixel panning
+    ac: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+        0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+        0x0c, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// what the BIOS boots into
+    Mode80x25,
+    Mode80x50,
+    Mode90x60,
+}
+
+impl TextMode {
+    pub const ALL: [TextMode; 3] = [TextMode::Mode80x25, TextMode::Mode80x50, TextMode::Mode90x60];
+
+    /// columns and rows
+    pub const fn size(self) -> (usize, usize) {
+        match self {
+            TextMode::Mode80x25 => (80, 25),
+            TextMode::Mode80x50 => (80, 50),
+            TextMode::Mode90x60 => (90, 60),
+        }
+    }
+
+    /// "80x25" and so on
+    pub fn parse(s: &str) -> Option<TextMode> {
+        TextMode::ALL.iter().copied().find(|mode| {
+            let (width, height) = mode.size();
+            let mut parts = s.split('x');
+            parts.next().and_then(|w| w.parse().ok()) == Some(width)
+                && parts.next().and_then(|h| h.parse().ok()) == Some(height)
+                && parts.next().is_none()
+        })
+    }
+
+    fn registers(self) -> &'static Registers {
+        match self {
+            TextMode::Mode80x25 => &REGS_80X25,
+            TextMode::Mode80x50 => &REGS_80X50,
+            TextMode::Mode90x60 => &REGS_90X60,
+        }
+    }
+
+    /// scan lines per character
+    fn char_height(self) -> usize {
+        (self.registers().crtc[0x09] & 0x1f) as usize + 1
+    }
+}
+
+fn write_indexed(index: u16, data: u16, reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(index).write(reg);
+        Port::<u8>::new(data).write(value);
+    }
+}
+
+fn read_indexed(index: u16, data: u16, reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(index).write(reg);
+        Port::<u8>::new(data).read()
+    }
+}
+
+fn program(regs: &Registers) {
+    unsafe { Port::<u8>::new(MISC_WRITE).write(regs.misc) };
+    for (i, &value) in regs.seq.iter().enumerate() {
+        write_indexed(SEQ_INDEX, SEQ_DATA, i as u8, value);
+    }
+
+    // CRTC registers 0-7 are write protected by a bit in 0x11, and 0x03
+    // has a bit that has to stay set for the others to be reachable
+    let mut crtc = regs.crtc;
+    crtc[CRTC_H_BLANK_END] |= 0x80;
+    crtc[CRTC_V_RETRACE_END] &= !0x80;
+    let h_blank_end = read_indexed(CRTC_INDEX, CRTC_DATA, CRTC_H_BLANK_END as u8);
+    write_indexed(CRTC_INDEX, CRTC_DATA, CRTC_H_BLANK_END as u8, h_blank_end | 0x80);
+    let v_retrace_end = read_indexed(CRTC_INDEX, CRTC_DATA, CRTC_V_RETRACE_END as u8);
+    write_indexed(CRTC_INDEX, CRTC_DATA, CRTC_V_RETRACE_END as u8, v_retrace_end & !0x80);
+    for (i, &value) in crtc.iter().enumerate() {
+        write_indexed(CRTC_INDEX, CRTC_DATA, i as u8, value);
+    }
+
+    for (i, &value) in regs.gc.iter().enumerate() {
+        write_indexed(GC_INDEX, GC_DATA, i as u8, value);
+    }
+
+    let mut input_status = Port::<u8>::new(INPUT_STATUS);
+    let mut ac = Port::<u8>::new(AC_WRITE);
+    for (i, &value) in regs.ac.iter().enumerate() {
+        unsafe {
+            input_status.read();
+            ac.write(i as u8);
+            ac.write(value);
+        }
+    }
+    unsafe {
+        input_status.read();
+        ac.write(AC_PALETTE_ENABLE);
+    }
+}
+
+/// Loads the built in font as the text mode font, fit to `height` scan lines.
+fn load_font(height: usize) {
+    let font = &*font::DEFAULT;
+    let seq_map_mask = read_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK);
+    let seq_memory_mode = read_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE);
+    let gc_read_map = read_indexed(GC_INDEX, GC_DATA, GC_READ_MAP);
+    let gc_mode = read_indexed(GC_INDEX, GC_DATA, GC_MODE);
+    let gc_misc = read_indexed(GC_INDEX, GC_DATA, GC_MISC);
+
+    // plain planar addressing instead of odd/even, only plane 2 written
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE, seq_memory_mode | 0x04);
+    write_indexed(GC_INDEX, GC_DATA, GC_MODE, gc_mode & !0x10);
+    write_indexed(GC_INDEX, GC_DATA, GC_MISC, gc_misc & !0x02);
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK, 1 << FONT_PLANE);
+    write_indexed(GC_INDEX, GC_DATA, GC_READ_MAP, FONT_PLANE);
+
+    let window = TEXT_WINDOW as *mut u8;
+    for index in 0..256 {
+        let glyph = font.glyph(index);
+        for line in 0..GLYPH_SLOT {
+            // squashing: OR together the font rows this scan line covers,
+            // a row is one byte as the font is 8 pixels wide
+            let bits = if line < height {
+                let from = line * font.height / height;
+                let to = ((line + 1) * font.height / height).max(from + 1);
+                glyph[from..to.min(font.height)].iter().fold(0, |bits, &row| bits | row)
+            } else {
+                0
+            };
+            unsafe { window.add(index * GLYPH_SLOT + line).write_volatile(bits) };
+        }
+    }
+
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MAP_MASK, seq_map_mask);
+    write_indexed(SEQ_INDEX, SEQ_DATA, SEQ_MEMORY_MODE, seq_memory_mode);
+    write_indexed(GC_INDEX, GC_DATA, GC_READ_MAP, gc_read_map);
+    write_indexed(GC_INDEX, GC_DATA, GC_MODE, gc_mode);
+    write_indexed(GC_INDEX, GC_DATA, GC_MISC, gc_misc);
+}
+
+/// Programs the VGA for `mode` and loads its font. What was in the text
+/// buffer is garbage afterwards, the console draws it again.
+pub fn set(mode: TextMode) {
+    program(mode.registers());
+    load_font(mode.char_height());
+}
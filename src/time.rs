@@ -0,0 +1,47 @@
+//! monotonic clock, counted from the PIT timer interrupt (IRQ0)
+//!
+//! the PIT is left at the BIOS default divisor (65536) so one tick is ~54.9ms
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// input clock of the 8253/8254 PIT in Hz
+pub const PIT_BASE_HZ: u64 = 1_193_182;
+/// divisor programmed by the BIOS, we never reprogram channel 0
+pub const PIT_DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// called once per timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// convert a number of PIT ticks to milliseconds
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * PIT_DIVISOR * 1000 / PIT_BASE_HZ
+}
+
+/// convert milliseconds to PIT ticks, rounding up so short waits still wait
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_BASE_HZ + PIT_DIVISOR * 1000 - 1) / (PIT_DIVISOR * 1000)
+}
+
+/// milliseconds since boot (monotonic, ~55ms resolution)
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// halt until at least `ms` milliseconds have passed.
+///
+/// interrupts have to be enabled, otherwise the tick count never moves
+pub fn sleep_ms(ms: u64) {
+    let end = ticks() + ms_to_ticks(ms);
+    while ticks() < end {
+        x86_64::instructions::hlt();
+    }
+}
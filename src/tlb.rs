@@ -0,0 +1,95 @@
+//! TLB shootdown
+//! https://wiki.osdev.org/TLB
+//!
+//! every CPU caches translations in its own TLB, so when a mapping other CPUs
+//! may have used is changed or removed, they have to drop it as well. the
+//! initiator flushes locally, publishes the range, sends `SHOOTDOWN_VECTOR` to
+//! every other online CPU and waits until each of them cleared its bit in
+//! `PENDING`. one shootdown is in flight at a time.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+use crate::apic;
+use crate::lock::Mutex;
+use crate::smp;
+use crate::{percpu, println_colored};
+use crate::vga_buffer::WARNING_COLOR;
+
+pub const SHOOTDOWN_VECTOR: u8 = 0xf0;
+
+/// above this many pages reloading CR3 is cheaper than `invlpg` for each one
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+/// how long to spin for the other CPUs. not measured with the timer since the
+/// initiator may well have interrupts disabled
+const ACK_SPINS: u64 = 100_000_000;
+
+/// the request currently in flight, `PAGES` 0 means the whole TLB
+static START: AtomicU64 = AtomicU64::new(0);
+static PAGES: AtomicU64 = AtomicU64::new(0);
+/// one bit per CPU that still has to flush
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// held by the CPU whose shootdown is in flight
+pub static LOCK: Mutex<()> = Mutex::new("TLB shootdown", ());
+
+fn flush_local(start: u64, pages: u64) {
+    if pages == 0 || pages > FULL_FLUSH_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(VirtAddr::new(start + i * 4096));
+        }
+    }
+}
+
+/// the shootdown IPI handler's work, also run by CPUs that wait for their own
+/// turn so two initiators can't wait for each other
+pub fn handle_shootdown() {
+    let bit = 1 << percpu::cpu_id();
+    if PENDING.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+    flush_local(START.load(Ordering::SeqCst), PAGES.load(Ordering::SeqCst));
+    PENDING.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/// make every CPU forget the translations for `pages` pages from `start`,
+/// 0 pages flushes everything. returns once all online CPUs are done
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    flush_local(start.as_u64(), pages);
+    if !apic::is_initialized() || smp::cpus_online() <= 1 {
+        return;
+    }
+
+    let targets = smp::other_online_cpus();
+    if targets == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = LOCK.try_lock() {
+            break guard;
+        }
+        handle_shootdown();
+        core::hint::spin_loop();
+    };
+
+    START.store(start.as_u64(), Ordering::SeqCst);
+    PAGES.store(pages, Ordering::SeqCst);
+    PENDING.store(targets, Ordering::SeqCst);
+
+    smp::send_ipi(targets, SHOOTDOWN_VECTOR);
+
+    for _ in 0..ACK_SPINS {
+        if PENDING.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    let stuck = PENDING.swap(0, Ordering::SeqCst);
+    if stuck != 0 {
+        println_colored!(WARNING_COLOR, "TLB: shootdown not acknowledged by CPU mask {:#x}", stuck);
+    }
+}
@@ -1,16 +1,27 @@
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::lock::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+use crate::ansi::{self, Action, Csi};
+use crate::cp437;
+use crate::font::{self, Font};
+use crate::framebuffer::{self, Framebuffer};
+use crate::text_mode::{self, TextMode};
+use crate::smp::MAX_CPUS;
+use crate::percpu;
 
 lazy_static! {
     /// A global `Writer` instance that can be used for printing to the VGA text buffer.
     ///
     /// Used by the `print!` and `println!` macros.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::LightGreen, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    pub static ref WRITER: Mutex<Writer> = Mutex::new("WRITER", Writer {
+        // WRITER is the only one to use the scrollbacks
+        consoles: unsafe { [Console::new(0), Console::new(1), Console::new(2), Console::new(3)] },
+        foreground: 0,
+        output: 0,
+        display: Display::Text(unsafe { &mut *(0xb8000 as *mut Buffer) }),
     });
 
 }
@@ -38,6 +49,19 @@ pub enum Color {
     White = 15,
 }
 
+/// What the colors look like in 0x00RRGGBB, for drawing them on a framebuffer.
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa,
+    0x555555, 0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+];
+
+/// Exception reports, panics and lockups.
+pub const ERROR_COLOR: Color = Color::LightRed;
+/// Something didn't work out but the kernel carries on.
+pub const WARNING_COLOR: Color = Color::Yellow;
+/// The shell prompt, so it stands out from command output.
+pub const PROMPT_COLOR: Color = Color::LightCyan;
+
 /// A combination of a foreground and a background color.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -45,11 +69,150 @@ struct ColorCode(u8);
 
 impl ColorCode {
     /// Create a new `ColorCode` with the given foreground and background colors.
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
 
+/// The ANSI colors 0-7 in SGR 30-37/40-47 order, and their bright versions
+/// (SGR 90-97/100-107, or bold).
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+];
+
+/// The SGR code for `color` from `normal` (30 or 40) or `bright` (90 or 100) up.
+fn ansi_code(color: Color, normal: u8, bright: u8) -> u8 {
+    match ANSI_COLORS.iter().position(|&c| c == color) {
+        Some(i) => normal + i as u8,
+        None => bright + ANSI_BRIGHT_COLORS.iter().position(|&c| c == color).unwrap_or(0) as u8,
+    }
+}
+
+/// The escape sequence that shows `Attributes` on a terminal, for the serial
+/// console. The default attributes are the terminal's own colors.
+struct Sgr(Attributes);
+
+impl fmt::Display for Sgr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let attributes = self.0;
+        f.write_str("\x1b[0")?;
+        if attributes.foreground != Attributes::DEFAULT.foreground {
+            write!(f, ";{}", ansi_code(attributes.foreground, 30, 90))?;
+        }
+        if attributes.background != Attributes::DEFAULT.background {
+            write!(f, ";{}", ansi_code(attributes.background, 40, 100))?;
+        }
+        if attributes.bold {
+            f.write_str(";1")?;
+        }
+        if attributes.reverse {
+            f.write_str(";7")?;
+        }
+        f.write_str("m")
+    }
+}
+
+/// The attributes of the output console and of the log console. The log gets
+/// a copy of everything written, so it has to change colors along with it.
+#[derive(Clone, Copy)]
+struct SavedAttributes {
+    output: Attributes,
+    log: Attributes,
+}
+
+/// What SGR escape sequences set, turned into a `ColorCode` for each character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        foreground: Color::LightGreen,
+        background: Color::Black,
+        bold: false,
+        reverse: false,
+    };
+
+    const fn color_code(&self) -> ColorCode {
+        let mut foreground = self.foreground;
+        // bold is bright, VGA text mode has no bold font
+        if self.bold {
+            let mut i = 0;
+            while i < ANSI_COLORS.len() {
+                if ANSI_COLORS[i] as u8 == foreground as u8 {
+                    foreground = ANSI_BRIGHT_COLORS[i];
+                }
+                i += 1;
+            }
+        }
+        if self.reverse {
+            ColorCode::new(self.background, foreground)
+        } else {
+            ColorCode::new(foreground, self.background)
+        }
+    }
+
+    /// applies SGR (`ESC [ ... m`) parameters
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            *self = Attributes::DEFAULT;
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => *self = Attributes::DEFAULT,
+                1 => self.bold = true,
+                2 | 22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                n @ 30..=37 => self.foreground = ANSI_COLORS[n as usize - 30],
+                39 => self.foreground = Attributes::DEFAULT.foreground,
+                n @ 40..=47 => self.background = ANSI_COLORS[n as usize - 40],
+                49 => self.background = Attributes::DEFAULT.background,
+                n @ 90..=97 => self.foreground = ANSI_BRIGHT_COLORS[n as usize - 90],
+                n @ 100..=107 => self.background = ANSI_BRIGHT_COLORS[n as usize - 100],
+                // 256 color (38;5;n) and true color (38;2;r;g;b), only the first 16 fit
+                n @ (38 | 48) => {
+                    let color = match params.get(i + 1) {
+                        Some(5) => {
+                            i += 2;
+                            match params.get(i) {
+                                Some(&c @ 0..=7) => Some(ANSI_COLORS[c as usize]),
+                                Some(&c @ 8..=15) => Some(ANSI_BRIGHT_COLORS[c as usize - 8]),
+                                _ => None,
+                            }
+                        }
+                        Some(2) => {
+                            i += 4;
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if n == 38 {
+                            self.foreground = color;
+                        } else {
+                            self.background = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
 /// A screen character in the VGA text buffer, consisting of an ASCII character and a `ColorCode`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
@@ -58,47 +221,239 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-/// The height of the text buffer (normally 25 lines).
-pub const BUFFER_HEIGHT: usize = 25;
-/// The width of the text buffer (normally 80 columns).
-pub const BUFFER_WIDTH: usize = 80;
+/// The most columns and rows a console can have, what the scrollback and the
+/// shell's line buffer are sized for. Larger screens show a console this big.
+pub const MAX_WIDTH: usize = 160;
+pub const MAX_HEIGHT: usize = 64;
+/// The smallest console the shell is still usable on.
+pub const MIN_WIDTH: usize = 40;
+pub const MIN_HEIGHT: usize = 10;
+
+/// The text mode the BIOS leaves us in.
+const BOOT_WIDTH: usize = TextMode::Mode80x25.size().0;
+const BOOT_HEIGHT: usize = TextMode::Mode80x25.size().1;
+
+/// Character cells in the 32KiB text buffer window at 0xb8000.
+const TEXT_BUFFER_CELLS: usize = 0x8000 / 2;
+
+/// How many lines the scrollback keeps, the ones on the screen included.
+pub const SCROLLBACK_LINES: usize = 500;
+
+/// Virtual consoles, on Alt+F1 to Alt+F4.
+pub const CONSOLES: usize = 4;
+/// The last one only shows a copy of everything printed on the others, it has
+/// no shell.
+pub const LOG_CONSOLE: usize = CONSOLES - 1;
+
+/// `Console::dirty` with every row set, whatever the height. There is one bit
+/// per row, hence `MAX_HEIGHT` of 64.
+const ALL_ROWS: u64 = u64::MAX;
 
-/// A structure representing the VGA text buffer.
+// CRTC registers, selected through the index port
+// https://wiki.osdev.org/Text_Mode_Cursor
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_MAX_SCAN_LINE: u8 = 0x09;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+/// in `CRTC_CURSOR_START`
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+fn read_crtc(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CRTC_INDEX).write(reg);
+        Port::<u8>::new(CRTC_DATA).read()
+    }
+}
+
+fn write_crtc(reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CRTC_INDEX).write(reg);
+        Port::<u8>::new(CRTC_DATA).write(value);
+    }
+}
+
+/// Which scan lines of the character cell the hardware cursor covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// the bottom two scan lines, the BIOS default
+    Underline,
+    HalfBlock,
+    Block,
+}
+
+/// A structure representing the VGA text buffer, the rows one after another
+/// as wide as the text mode is.
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [Volatile<ScreenChar>; TEXT_BUFFER_CELLS],
+}
+
+/// A text grid drawn with a bitmap font on a framebuffer, centered on the
+/// screen. The cursor is drawn into the cell it's on by inverting its colors.
+struct Graphics {
+    framebuffer: Framebuffer,
+    font: &'static Font,
+    /// the pixel the top left cell starts at
+    left: usize,
+    top: usize,
+    /// where the cursor was drawn, its cell needs drawing again once it moves
+    cursor: Option<(usize, usize)>,
+    cursor_shape: CursorShape,
+}
+
+impl Graphics {
+    /// Draws one character cell, with the cursor on it if `cursor`.
+    fn draw(&mut self, row: usize, col: usize, character: ScreenChar, cursor: bool) {
+        let ColorCode(code) = character.color_code;
+        let foreground = PALETTE[(code & 0xf) as usize];
+        let background = PALETTE[(code >> 4) as usize];
+        let (width, height) = (self.font.width, self.font.height);
+        let cursor_from = match (cursor, self.cursor_shape) {
+            (false, _) => height,
+            (true, CursorShape::Underline) => height.saturating_sub(2),
+            (true, CursorShape::HalfBlock) => height / 2,
+            (true, CursorShape::Block) => 0,
+        };
+        let glyph = self.font.glyph(character.ascii_character as usize);
+        let (x, y) = (self.left + col * width, self.top + row * height);
+        for gy in 0..height {
+            let inverted = gy >= cursor_from;
+            for gx in 0..width {
+                let set = self.font.pixel(glyph, gx, gy) != inverted;
+                self.framebuffer.put_pixel(x + gx, y + gy, if set { foreground } else { background });
+            }
+        }
+    }
+}
+
+/// Where `Writer` shows the foreground console.
+enum Display {
+    /// the VGA text buffer at 0xb8000
+    Text(&'static mut Buffer),
+    Graphics(Graphics),
+}
+
+/// Every line that went past on the screen, in a ring. The newest lines, as
+/// many as the console is high, are what the screen shows when it isn't
+/// scrolled back, so this is also what the console reads its own contents
+/// from. Lines are kept `MAX_WIDTH` wide whatever the console width.
+struct Scrollback {
+    lines: [[ScreenChar; MAX_WIDTH]; SCROLLBACK_LINES],
+    /// the line on the last row of the screen
+    bottom: usize,
+    /// how many lines hold something, up to `SCROLLBACK_LINES`
+    used: usize,
 }
 
-/// A writer type that allows writing ASCII bytes and strings to an underlying `Buffer`.
+impl Scrollback {
+    const fn new() -> Scrollback {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new(Color::LightGreen, Color::Black),
+        };
+        Scrollback {
+            lines: [[blank; MAX_WIDTH]; SCROLLBACK_LINES],
+            bottom: BOOT_HEIGHT - 1,
+            used: BOOT_HEIGHT,
+        }
+    }
+
+    /// the line `back` lines above the last row of the screen
+    fn line(&mut self, back: usize) -> &mut [ScreenChar; MAX_WIDTH] {
+        &mut self.lines[(self.bottom + SCROLLBACK_LINES - back) % SCROLLBACK_LINES]
+    }
+
+    /// start a new line below the last one, dropping the oldest once full
+    fn push(&mut self, blank: ScreenChar) {
+        self.bottom = (self.bottom + 1) % SCROLLBACK_LINES;
+        self.used = (self.used + 1).min(SCROLLBACK_LINES);
+        self.lines[self.bottom] = [blank; MAX_WIDTH];
+    }
+}
+
+const EMPTY_SCROLLBACK: Scrollback = Scrollback::new();
+
+/// One per console. In .bss rather than in `WRITER`, the lazy_static
+/// initializer would build them on the stack first.
+static mut SCROLLBACKS: [Scrollback; CONSOLES] = [EMPTY_SCROLLBACK; CONSOLES];
+
+/// A virtual console: its screen and scrollback, cursor and colors. Only kept
+/// in memory, `Writer` copies the one in the foreground to the VGA buffer.
 ///
-/// Wraps lines at `BUFFER_WIDTH`. Supports newline characters and implements the
-/// `core::fmt::Write` trait.
-pub struct Writer {
-    // TODO: track row position too?
+/// Wraps lines at its width. Supports newline characters and ANSI escape
+/// sequences (see `Console::csi`).
+struct Console {
+    /// columns and rows, the same for every console
+    width: usize,
+    height: usize,
+    row_position: usize,
     column_position: usize,
+    cursor_visible: bool,
+    /// what new characters are written with, always `attributes.color_code()`
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    attributes: Attributes,
+    /// ESC 7 / CSI s: row, column and attributes for ESC 8 / CSI u
+    saved: (usize, usize, Attributes),
+    /// the rows a line feed scrolls, both inclusive (CSI r)
+    scroll_top: usize,
+    scroll_bottom: usize,
+    parser: ansi::Parser,
+    scrollback: &'static mut Scrollback,
+    /// how many lines the view is scrolled back from the bottom
+    view_offset: usize,
+    /// screen rows that changed since `Writer::flush` last drew them, one bit each
+    dirty: u64,
+    /// was switched to before, for the shell to know when to print its first prompt
+    shown: bool,
 }
 
-impl Writer {
-    /// Writes an ASCII byte to the buffer.
+impl Console {
+    /// Console `i` at the boot text mode size, only console 0 starts out shown.
+    ///
+    /// Unsafe because it takes `SCROLLBACKS[i]`, there can only be one console
+    /// for each.
+    unsafe fn new(i: usize) -> Console {
+        Console {
+            width: BOOT_WIDTH,
+            height: BOOT_HEIGHT,
+            row_position: BOOT_HEIGHT - 1,
+            column_position: 0,
+            cursor_visible: true,
+            color_code: Attributes::DEFAULT.color_code(),
+            attributes: Attributes::DEFAULT,
+            saved: (BOOT_HEIGHT - 1, 0, Attributes::DEFAULT),
+            scroll_top: 0,
+            scroll_bottom: BOOT_HEIGHT - 1,
+            parser: ansi::Parser::new(),
+            scrollback: &mut *core::ptr::addr_of_mut!(SCROLLBACKS[i]),
+            view_offset: 0,
+            dirty: ALL_ROWS,
+            shown: i == 0,
+        }
+    }
+
+    /// Writes a code page 437 byte to the buffer.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
+    /// Wraps lines at the console width. Supports the `\n` newline character.
     /// #: doesn't need to write every single char (refresh display) again 
     /// because those characters will already be on the screen/device mem
-    pub fn write_byte(&mut self, byte: u8) {
+    fn write_byte(&mut self, byte: u8) {
+        self.scroll_to_bottom();
         match byte {
             b'\n' => self.new_line(),
             byte => {
-                if self.column_position >= BUFFER_WIDTH {
+                if self.column_position >= self.width {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.put(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code,
                 });
@@ -107,62 +462,359 @@ impl Writer {
         }
     }
 
-    /// Writes the given ASCII string to the buffer.
+    /// Writes the given string to the buffer, interpreting escape sequences.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character. Does **not**
-    /// support strings with non-ASCII characters, since they can't be printed in the VGA text
-    /// mode.
+    /// Wraps lines at the console width. Supports the `\n` newline character. Characters
+    /// outside ASCII are shown as their code page 437 glyph, the ones the VGA font
+    /// doesn't have as `cp437::FALLBACK`.
     fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                None => {}
+                Some(Action::Print(c)) => self.write_byte(cp437::from_char(c).unwrap_or(cp437::FALLBACK)),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi(csi)) => self.csi(&csi),
             }
         }
     }
 
-    /// Shifts all lines one line up and clears the last row.
-    /// interpret line input if a command matches, by storing the last line
-    fn new_line(&mut self) {
-        
-        // this nested loop shifts all lines up line up
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    /// C0 control characters, the ones a console is expected to know
+    fn control(&mut self, c: char) {
+        self.scroll_to_bottom();
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            '\x08' => self.column_position = self.column_position.saturating_sub(1),
+            '\t' => {
+                let next = (self.column_position / 8 + 1) * 8;
+                while self.column_position < next.min(self.width) {
+                    self.write_byte(b' ');
+                }
             }
+            // BEL and the rest
+            _ => {}
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    pub fn get_prev_line(&self,  line: &mut [char; BUFFER_WIDTH]) {
-        // let line: [char; BUFFER_WIDTH];
+    /// `ESC final`
+    fn escape(&mut self, c: char) {
+        self.scroll_to_bottom();
+        match c {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            // index: line feed without carriage return
+            'D' => self.line_feed(),
+            // next line
+            'E' => self.new_line(),
+            // reverse index
+            'M' => {
+                if self.row_position == self.scroll_top {
+                    self.scroll_region_down(1);
+                } else if self.row_position > 0 {
+                    self.row_position -= 1;
+                }
+            }
+            // reset to initial state
+            'c' => self.reset(),
+            _ => {}
+        }
+    }
 
-        for col in 1..BUFFER_WIDTH {
-            line[col-1] = self.buffer.chars[BUFFER_HEIGHT-1][col].read().ascii_character as char;
-            // self.buffer.chars[row - 1][col].write(character);
+    /// `ESC [ params final`: SGR colors, cursor movement, erasing, save and
+    /// restore, scroll regions and showing/hiding the cursor
+    fn csi(&mut self, csi: &Csi) {
+        self.scroll_to_bottom();
+        let n = csi.param(0, 1) as usize;
+        let last_row = self.height - 1;
+        let last_col = self.width - 1;
+        match csi.action {
+            'm' => {
+                let mut attributes = self.attributes;
+                attributes.select_graphic_rendition(csi.params());
+                self.set_attributes(attributes);
+            }
+            'A' => self.row_position = self.row_position.saturating_sub(n),
+            'B' => self.row_position = (self.row_position + n).min(last_row),
+            'C' => self.column_position = (self.column_position + n).min(last_col),
+            'D' => self.column_position = self.column_position.min(last_col).saturating_sub(n),
+            // next/previous line
+            'E' => {
+                self.row_position = (self.row_position + n).min(last_row);
+                self.column_position = 0;
+            }
+            'F' => {
+                self.row_position = self.row_position.saturating_sub(n);
+                self.column_position = 0;
+            }
+            // column, row, both
+            'G' => self.column_position = (n - 1).min(last_col),
+            'd' => self.row_position = (n - 1).min(last_row),
+            'H' | 'f' => {
+                self.row_position = (n - 1).min(last_row);
+                self.column_position = (csi.param(1, 1) as usize - 1).min(last_col);
+            }
+            // erase in display: to the end, from the start, all
+            'J' => {
+                let (row, col) = (self.row_position, self.column_position.min(last_col));
+                match csi.param(0, 0) {
+                    0 => {
+                        self.erase(row, col, self.width);
+                        for below in row + 1..self.height {
+                            self.clear_row(below);
+                        }
+                    }
+                    1 => {
+                        for above in 0..row {
+                            self.clear_row(above);
+                        }
+                        self.erase(row, 0, col + 1);
+                    }
+                    _ => {
+                        for row in 0..self.height {
+                            self.clear_row(row);
+                        }
+                    }
+                }
+            }
+            // erase in line, the same three
+            'K' => {
+                let (row, col) = (self.row_position, self.column_position.min(last_col));
+                match csi.param(0, 0) {
+                    0 => self.erase(row, col, self.width),
+                    1 => self.erase(row, 0, col + 1),
+                    _ => self.clear_row(row),
+                }
+            }
+            // scroll the region up/down
+            'S' => self.scroll_region_up(n),
+            'T' => self.scroll_region_down(n),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            // set the scroll region, which also homes the cursor
+            'r' => {
+                let top = n - 1;
+                let bottom = csi.param(1, self.height as u16) as usize - 1;
+                if top < bottom && bottom < self.height {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.row_position = 0;
+                    self.column_position = 0;
+                }
+            }
+            // DECTCEM, ESC [ ? 25 h/l
+            'h' | 'l' if csi.private && csi.param(0, 0) == 25 => {
+                self.cursor_visible = csi.action == 'h';
+            }
+            _ => {}
         }
     }
 
-    /// Clears a row by overwriting it with blank characters.
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
+    fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes;
+        self.color_code = attributes.color_code();
+    }
+
+    fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.set_attributes(Attributes { foreground, background, ..self.attributes });
+    }
+
+    /// Back to the default colors, and out of a half written escape sequence.
+    fn reset_attributes(&mut self) {
+        self.parser.reset();
+        self.set_attributes(Attributes::DEFAULT);
+    }
+
+    /// Everything escape sequences can change back to the start, and a clear screen.
+    fn reset(&mut self) {
+        self.reset_attributes();
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.clear_screen();
+    }
+
+    /// Changes the size. The screen grows or shrinks at the top so the cursor
+    /// stays on the line it was on, as far as that's still on the screen.
+    /// Lines wider than the console keep what's past its edge for later.
+    fn resize(&mut self, width: usize, height: usize) {
+        self.scroll_to_bottom();
+        self.row_position = (self.row_position + height).saturating_sub(self.height).min(height - 1);
+        self.column_position = self.column_position.min(width);
+        let (row, col, attributes) = self.saved;
+        self.saved = (row.min(height - 1), col.min(width - 1), attributes);
+        self.width = width;
+        self.height = height;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+        self.scrollback.used = self.scrollback.used.max(height);
+        self.redraw();
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = (self.row_position, self.column_position, self.attributes);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col, attributes) = self.saved;
+        self.row_position = row;
+        self.column_position = col;
+        self.set_attributes(attributes);
+    }
+
+    /// Moves to the start of the next row.
+    fn new_line(&mut self) {
+        self.line_feed();
+        self.column_position = 0;
+    }
+
+    /// Moves down a row. On the last row of the scroll region this shifts its
+    /// lines one line up and clears the last one. With the region being the
+    /// whole screen row 0 isn't lost, it stays in the scrollback
+    fn line_feed(&mut self) {
+        if self.row_position == self.scroll_bottom {
+            self.scroll_region_up(1);
+        } else if self.row_position < self.height - 1 {
+            self.row_position += 1;
+        }
+    }
+
+    /// A blank in the current colors, for erasing.
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
         }
     }
 
+    /// Scrolls the lines of the scroll region up, blank lines come in at the bottom.
+    fn scroll_region_up(&mut self, lines: usize) {
+        let blank = self.blank();
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        if top == 0 && bottom == self.height - 1 {
+            for _ in 0..lines.min(self.height) {
+                self.scrollback.push(blank);
+            }
+        } else {
+            let lines = lines.min(bottom - top + 1);
+            for row in top..=bottom {
+                *self.screen_line(row) = if row + lines <= bottom {
+                    *self.screen_line(row + lines)
+                } else {
+                    [blank; MAX_WIDTH]
+                };
+            }
+        }
+        self.redraw();
+    }
+
+    /// Scrolls the lines of the scroll region down, blank lines come in at the top.
+    fn scroll_region_down(&mut self, lines: usize) {
+        let blank = self.blank();
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let lines = lines.min(bottom - top + 1);
+        for row in (top..=bottom).rev() {
+            *self.screen_line(row) = if row >= top + lines {
+                *self.screen_line(row - lines)
+            } else {
+                [blank; MAX_WIDTH]
+            };
+        }
+        self.redraw();
+    }
+
+    /// Blanks columns `from..to` of a row.
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        let blank = self.blank();
+        for col in from..to {
+            self.put(row, col, blank);
+        }
+    }
+
+    /// The scrollback line on screen row `row` when not scrolled back.
+    fn screen_line(&mut self, row: usize) -> &mut [ScreenChar; MAX_WIDTH] {
+        self.scrollback.line(self.height - 1 - row)
+    }
+
+    /// Writes a character on a screen row.
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.screen_line(row)[col] = character;
+        self.dirty |= 1 << row;
+    }
+
+    /// Everything in view has to be drawn again.
+    fn redraw(&mut self) {
+        self.dirty = ALL_ROWS;
+    }
+
+    /// The line on screen row `row`, scrolled back or not.
+    fn view_line(&mut self, row: usize) -> &[ScreenChar; MAX_WIDTH] {
+        self.scrollback.line(self.height - 1 - row + self.view_offset)
+    }
+
+    /// Shows `lines` older lines, as far back as the scrollback goes.
+    fn scroll_up(&mut self, lines: usize) {
+        let oldest = self.scrollback.used - self.height;
+        let offset = (self.view_offset + lines).min(oldest);
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw();
+        }
+    }
+
+    /// Shows `lines` newer lines, down to the bottom.
+    fn scroll_down(&mut self, lines: usize) {
+        let offset = self.view_offset.saturating_sub(lines);
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw();
+        }
+    }
+
+    /// Back to the live screen, any output does this first.
+    fn scroll_to_bottom(&mut self) {
+        self.scroll_down(self.view_offset);
+    }
+
+    /// the row the cursor is on without the prompt in column 0, from the
+    /// scrollback so it's right while scrolled back. returns its length
+    fn get_prev_line(&mut self, line: &mut [char; MAX_WIDTH]) -> usize {
+        let current = *self.screen_line(self.row_position);
+        for col in 1..self.width {
+            line[col-1] = cp437::to_char(current[col].ascii_character);
+        }
+        self.width - 1
+    }
+
+    /// Blanks the whole screen, output continues at the top left.
+    fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..self.height {
+            self.clear_row(row);
+        }
+        self.row_position = 0;
+        self.column_position = 0;
+    }
+
+    /// Where the hardware cursor goes, None while it's hidden or the view
+    /// is scrolled back.
+    fn cursor(&self) -> Option<(usize, usize)> {
+        if !self.cursor_visible || self.view_offset != 0 {
+            return None;
+        }
+        // right after the last column the next character wraps, show it on the last one
+        Some((self.row_position, self.column_position.min(self.width - 1)))
+    }
+
+    /// Clears a row by overwriting it with blank characters.
+    fn clear_row(&mut self, row: usize) {
+        self.erase(row, 0, self.width);
+    }
+
     /// deleting charracters before (backspace) or after (DEL)
     /// might need to support arrow keys and cursors eventually too so that
     /// DEL works properly
     /// if backspace, count should be negative (go backwards)
-    pub fn _backspace(&mut self, backwards: bool) {
+    fn _backspace(&mut self, backwards: bool) {
         // count can be positive or negative, basically clears bytes
         // in that "direction" in the buffer
         if backwards {
@@ -173,26 +825,25 @@ impl Writer {
             }
             
         } else {
-            if self.column_position < BUFFER_WIDTH { // don't let it run over
+            if self.column_position < self.width { // don't let it run over
                 self.column_position += 1;
                 self.write_byte(' ' as u8);
                 self.column_position += 1; 
             }
         }
-        
     }
 
-    pub fn _cursor_left(&mut self) {
+    fn _cursor_left(&mut self) {
+        self.scroll_to_bottom();
 
         if self.column_position > 0 { // don't let it run over
             self.column_position -= 1; 
         }
-        
-        
     }
 
-    pub fn _cursor_right(&mut self) {
-        if self.column_position < BUFFER_WIDTH { // don't let it run over
+    fn _cursor_right(&mut self) {
+        self.scroll_to_bottom();
+        if self.column_position < self.width { // don't let it run over
             self.column_position += 1;
         }
     }
@@ -200,9 +851,294 @@ impl Writer {
     // add cursor up and down?
 }
 
+/// The virtual consoles and the display showing one of them, the VGA text
+/// buffer or a framebuffer.
+///
+/// `print!` goes to the output console and a copy to `LOG_CONSOLE`. Implements
+/// the `core::fmt::Write` trait.
+pub struct Writer {
+    consoles: [Console; CONSOLES],
+    /// the console on the screen
+    foreground: usize,
+    /// the shell console last switched to, where output and typing go
+    output: usize,
+    display: Display,
+}
+
+impl Writer {
+    /// Writes a code page 437 byte to the output console.
+    pub fn write_byte(&mut self, byte: u8) {
+        self.consoles[self.output].write_byte(byte);
+        self.flush();
+    }
+
+    /// Draws what changed on the foreground console, and puts the cursor
+    /// where it is.
+    fn flush(&mut self) {
+        let console = &mut self.consoles[self.foreground];
+        let mut dirty = core::mem::replace(&mut console.dirty, 0);
+        let cursor = console.cursor();
+        let (width, height) = (console.width, console.height);
+        match &mut self.display {
+            Display::Text(buffer) => {
+                for row in (0..height).filter(|row| dirty & (1 << row) != 0) {
+                    let line = *console.view_line(row);
+                    for (col, &ch) in line.iter().take(width).enumerate() {
+                        buffer.chars[row * width + col].write(ch);
+                    }
+                }
+
+                let start = read_crtc(CRTC_CURSOR_START);
+                match cursor {
+                    Some((row, col)) => {
+                        let position = (row * width + col) as u16;
+                        write_crtc(CRTC_CURSOR_LOW, position as u8);
+                        write_crtc(CRTC_CURSOR_HIGH, (position >> 8) as u8);
+                        write_crtc(CRTC_CURSOR_START, start & !CURSOR_DISABLE);
+                    }
+                    None => write_crtc(CRTC_CURSOR_START, start | CURSOR_DISABLE),
+                }
+            }
+            Display::Graphics(screen) => {
+                // the cell the cursor left has to lose it, the one it's on to get it
+                if screen.cursor != cursor {
+                    for &(row, _) in screen.cursor.iter().chain(cursor.iter()) {
+                        dirty |= 1 << row;
+                    }
+                    screen.cursor = cursor;
+                }
+                for row in (0..height).filter(|row| dirty & (1 << row) != 0) {
+                    let line = *console.view_line(row);
+                    for (col, &ch) in line.iter().take(width).enumerate() {
+                        screen.draw(row, col, ch, cursor == Some((row, col)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shows the consoles on a framebuffer from now on, with the built in
+    /// font, as many columns and rows as fit up to `MAX_WIDTH`x`MAX_HEIGHT`.
+    /// Returns false if not even `MIN_WIDTH`x`MIN_HEIGHT` fit.
+    pub fn use_framebuffer(&mut self, mut framebuffer: Framebuffer) -> bool {
+        let font: &'static Font = &font::DEFAULT;
+        let columns = (framebuffer.width / font.width).min(MAX_WIDTH);
+        let rows = (framebuffer.height / font.height).min(MAX_HEIGHT);
+        if columns < MIN_WIDTH || rows < MIN_HEIGHT {
+            return false;
+        }
+        framebuffer.clear(PALETTE[Color::Black as usize]);
+        let cursor_shape = self.cursor_shape();
+        self.display = Display::Graphics(Graphics {
+            left: (framebuffer.width - columns * font.width) / 2,
+            top: (framebuffer.height - rows * font.height) / 2,
+            framebuffer,
+            font,
+            cursor: None,
+            cursor_shape,
+        });
+        self.resize(columns, rows);
+        true
+    }
+
+    /// Back to VGA text mode, in one of the modes it can be programmed for.
+    /// Also what brings text mode back after a framebuffer.
+    pub fn set_text_mode(&mut self, mode: TextMode) {
+        let cursor_shape = self.cursor_shape();
+        if let Display::Graphics(_) = self.display {
+            framebuffer::disable();
+        }
+        text_mode::set(mode);
+        self.display = Display::Text(unsafe { &mut *(0xb8000 as *mut Buffer) });
+        // the mode comes with its own cursor scan lines
+        self.set_cursor_shape(cursor_shape);
+        let (width, height) = mode.size();
+        self.resize(width, height);
+    }
+
+    /// Columns and rows of the consoles.
+    pub fn size(&self) -> (usize, usize) {
+        let console = &self.consoles[self.foreground];
+        (console.width, console.height)
+    }
+
+    /// Gives every console a new size and draws the one on the screen again.
+    fn resize(&mut self, width: usize, height: usize) {
+        for console in self.consoles.iter_mut() {
+            console.resize(width, height);
+        }
+        self.flush();
+    }
+
+    /// Puts another console on the screen. A shell console also becomes the
+    /// output console. Returns whether that's the first time it's shown.
+    pub fn switch_to(&mut self, console: usize) -> bool {
+        if console >= CONSOLES {
+            return false;
+        }
+        self.foreground = console;
+        if console != LOG_CONSOLE {
+            self.output = console;
+        }
+        let first = !core::mem::replace(&mut self.consoles[console].shown, true);
+        self.consoles[console].redraw();
+        self.flush();
+        first
+    }
+
+    pub fn foreground(&self) -> usize {
+        self.foreground
+    }
+
+    /// Writes to the output console only, for echoing what's typed.
+    pub fn echo(&mut self, s: &str) {
+        self.consoles[self.output].write_string(s);
+        self.flush();
+    }
+
+    /// Shows `lines` older lines of the foreground console.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.consoles[self.foreground].scroll_up(lines);
+        self.flush();
+    }
+
+    /// Shows `lines` newer lines of the foreground console.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.consoles[self.foreground].scroll_down(lines);
+        self.flush();
+    }
+
+    /// Whether the view shows older lines instead of the live screen.
+    pub fn is_scrolled_back(&self) -> bool {
+        self.consoles[self.foreground].view_offset != 0
+    }
+
+    /// the row the cursor is on in the output console, for the shell.
+    /// returns how much of `line` it filled
+    pub fn get_prev_line(&mut self, line: &mut [char; MAX_WIDTH]) -> usize {
+        self.consoles[self.output].get_prev_line(line)
+    }
+
+    /// Blanks the output console, output continues at the top left.
+    pub fn clear_screen(&mut self) {
+        self.consoles[self.output].clear_screen();
+        self.flush();
+    }
+
+    fn attributes(&self) -> SavedAttributes {
+        SavedAttributes {
+            output: self.consoles[self.output].attributes,
+            log: self.consoles[LOG_CONSOLE].attributes,
+        }
+    }
+
+    fn set_attributes(&mut self, saved: SavedAttributes) {
+        self.consoles[self.output].set_attributes(saved.output);
+        self.consoles[LOG_CONSOLE].set_attributes(saved.log);
+    }
+
+    /// Sets the colors for what's written next, bold and reverse stay as they are.
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        for console in [self.output, LOG_CONSOLE] {
+            self.consoles[console].set_colors(foreground, background);
+        }
+    }
+
+    /// Sets the foreground color for what's written next, each console keeps
+    /// its background.
+    pub fn set_foreground(&mut self, foreground: Color) {
+        for console in [self.output, LOG_CONSOLE] {
+            let background = self.consoles[console].attributes.background;
+            self.consoles[console].set_colors(foreground, background);
+        }
+    }
+
+    /// Back to the default colors, and out of half written escape sequences.
+    pub fn reset_attributes(&mut self) {
+        for console in self.consoles.iter_mut() {
+            console.reset_attributes();
+        }
+    }
+
+    /// Everything escape sequences can change back to the start on every
+    /// console and clear screens, except the log's. The first console comes
+    /// to the front.
+    pub fn reset(&mut self) {
+        for (i, console) in self.consoles.iter_mut().enumerate() {
+            if i == LOG_CONSOLE {
+                console.reset_attributes();
+                console.scroll_to_bottom();
+            } else {
+                console.reset();
+                console.shown = false;
+            }
+        }
+        self.switch_to(0);
+    }
+
+    pub fn show_cursor(&mut self, visible: bool) {
+        self.consoles[self.output].cursor_visible = visible;
+        self.flush();
+    }
+
+    /// Sets the cursor shape, relative to the height of the current font.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        if let Display::Graphics(screen) = &mut self.display {
+            screen.cursor_shape = shape;
+            // drawn again with the new shape
+            screen.cursor = None;
+            self.flush();
+            return;
+        }
+        let height = (read_crtc(CRTC_MAX_SCAN_LINE) & 0x1f) + 1;
+        let (start, end) = match shape {
+            CursorShape::Underline => (height.saturating_sub(2), height - 1),
+            CursorShape::HalfBlock => (height / 2, height - 1),
+            CursorShape::Block => (0, height - 1),
+        };
+        let disabled = read_crtc(CRTC_CURSOR_START) & CURSOR_DISABLE;
+        // the other bits of the end register are the cursor skew, keep them
+        let skew = read_crtc(CRTC_CURSOR_END) & !0x1f;
+        write_crtc(CRTC_CURSOR_START, disabled | start);
+        write_crtc(CRTC_CURSOR_END, skew | end);
+    }
+
+    /// The cursor shape, in text mode worked out from its scan lines.
+    fn cursor_shape(&self) -> CursorShape {
+        if let Display::Graphics(screen) = &self.display {
+            return screen.cursor_shape;
+        }
+        let height = (read_crtc(CRTC_MAX_SCAN_LINE) & 0x1f) + 1;
+        match read_crtc(CRTC_CURSOR_START) & 0x1f {
+            0 => CursorShape::Block,
+            start if start <= height / 2 => CursorShape::HalfBlock,
+            _ => CursorShape::Underline,
+        }
+    }
+
+    pub fn _backspace(&mut self, backwards: bool) {
+        self.consoles[self.output]._backspace(backwards);
+        self.flush();
+    }
+
+    pub fn _cursor_left(&mut self) {
+        self.consoles[self.output]._cursor_left();
+        self.flush();
+    }
+
+    pub fn _cursor_right(&mut self) {
+        self.consoles[self.output]._cursor_right();
+        self.flush();
+    }
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
+        self.consoles[self.output].write_string(s);
+        if self.output != LOG_CONSOLE {
+            self.consoles[LOG_CONSOLE].write_string(s);
+        }
+        self.flush();
         Ok(())
     }
 }
@@ -220,17 +1156,119 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-/// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
+/// Like `print!`, in the given foreground `Color`.
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => ($crate::vga_buffer::_print_colored($color, format_args!($($arg)*)));
+}
+
+/// Like `println!`, in the given foreground `Color`.
+#[macro_export]
+macro_rules! println_colored {
+    ($color:expr) => ($crate::print!("\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::print_colored!($color, "{}\n", format_args!($($arg)*)));
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_EMERGENCY: AtomicBool = AtomicBool::new(false);
+/// CPUs whose output doesn't take the console locks, see `set_emergency`
+static EMERGENCY: [AtomicBool; MAX_CPUS] = [NO_EMERGENCY; MAX_CPUS];
+
+/// While on, `print!` on this CPU only goes to the serial console and takes
+/// no locks, and colors are left alone. For reports about code that may be
+/// holding the console locks itself and will carry on afterwards.
+pub fn set_emergency(on: bool) {
+    EMERGENCY[percpu::cpu_id()].store(on, Ordering::SeqCst);
+}
+
+fn is_emergency() -> bool {
+    EMERGENCY[percpu::cpu_id()].load(Ordering::SeqCst)
+}
+
+/// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance,
+/// and to the serial console.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    if is_emergency() {
+        crate::serial::emergency_print(args);
+        return;
+    }
     interrupts::without_interrupts(|| {     // disable interrupts as long as the Mutex is locked:
         WRITER.lock().write_fmt(args).unwrap();
+        crate::serial::_print(args);
     });
 }
 
+/// Like `_print`, but in another foreground color.
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    if is_emergency() {
+        crate::serial::emergency_print(args);
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.attributes();
+        writer.set_foreground(foreground);
+        let colored = writer.attributes();
+        writer.write_fmt(args).unwrap();
+        writer.set_attributes(previous);
+        drop(writer);
+        crate::serial::_print(format_args!("{}{}{}", Sgr(colored.output), args, Sgr(previous.output)));
+    });
+}
+
+/// Puts the colors back the way they were before `set_color` when dropped.
+#[must_use]
+pub struct ColorGuard {
+    /// None when nothing was changed, see `set_emergency`
+    previous: Option<SavedAttributes>,
+}
+
+impl Drop for ColorGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                WRITER.lock().set_attributes(previous);
+                crate::serial::_print(format_args!("{}", Sgr(previous.output)));
+            });
+        }
+    }
+}
+
+/// changes the colors with `change`, also on the serial console
+fn change_colors(change: impl FnOnce(&mut Writer)) -> ColorGuard {
+    if is_emergency() {
+        return ColorGuard { previous: None };
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.attributes();
+        change(&mut writer);
+        let colored = writer.attributes();
+        drop(writer);
+        crate::serial::_print(format_args!("{}", Sgr(colored.output)));
+        ColorGuard { previous: Some(previous) }
+    })
+}
+
+/// Prints in these colors until the returned guard goes out of scope, for
+/// blocks of output like an exception report. Other CPUs printing meanwhile
+/// get the same colors, `print_colored!` doesn't have that problem.
+pub fn set_color(foreground: Color, background: Color) -> ColorGuard {
+    change_colors(|writer| writer.set_colors(foreground, background))
+}
+
+/// `set_color` keeping the background.
+pub fn set_foreground(foreground: Color) -> ColorGuard {
+    change_colors(|writer| writer.set_foreground(foreground))
+}
 
 pub fn backspace(backwards: bool) {
 
@@ -248,3 +1286,59 @@ pub fn cursor_right() {
     WRITER.lock()._cursor_right();
 }
 
+/// Shift+PageUp
+pub fn page_up() {
+    let mut writer = WRITER.lock();
+    let (_, height) = writer.size();
+    writer.scroll_up(height / 2);
+}
+
+/// Shift+PageDown
+pub fn page_down() {
+    let mut writer = WRITER.lock();
+    let (_, height) = writer.size();
+    writer.scroll_down(height / 2);
+}
+
+pub fn show_cursor(visible: bool) {
+    WRITER.lock().show_cursor(visible);
+}
+
+pub fn set_cursor_shape(shape: CursorShape) {
+    WRITER.lock().set_cursor_shape(shape);
+}
+
+/// The smallest resolution the console fits on with the built in font.
+pub fn min_framebuffer_size() -> (usize, usize) {
+    (MIN_WIDTH * font::DEFAULT.width, MIN_HEIGHT * font::DEFAULT.height)
+}
+
+/// Columns and rows of the consoles, see `Writer::size`.
+pub fn size() -> (usize, usize) {
+    WRITER.lock().size()
+}
+
+/// See `Writer::set_text_mode`.
+pub fn set_text_mode(mode: TextMode) {
+    WRITER.lock().set_text_mode(mode);
+}
+
+/// Moves the console onto a framebuffer, see `Writer::use_framebuffer`.
+pub fn use_framebuffer(framebuffer: Framebuffer) -> bool {
+    WRITER.lock().use_framebuffer(framebuffer)
+}
+
+/// Alt+F1 to Alt+F4, returns whether the console is shown for the first time.
+pub fn switch_console(console: usize) -> bool {
+    WRITER.lock().switch_to(console)
+}
+
+pub fn foreground_console() -> usize {
+    WRITER.lock().foreground()
+}
+
+/// Prints what's typed on the output console, without copying it to the log.
+pub fn echo(character: char) {
+    let mut buf = [0; 4];
+    WRITER.lock().echo(character.encode_utf8(&mut buf));
+}
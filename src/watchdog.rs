@@ -0,0 +1,189 @@
+//! NMI watchdog: lockup detection
+//! https://wiki.osdev.org/Non_Maskable_Interrupt
+//!
+//! the PIT is routed through the I/O APIC as an NMI to every CPU, next to
+//! its normal route through the PIC. NMIs get through with interrupts
+//! disabled, so each CPU gets to look at itself ~18 times a second whatever
+//! it's doing. a CPU that is neither idle nor bumped its heartbeat (the timer
+//! interrupt and the idle loop do) for `timeout` seconds is reported once:
+//! where it is, its backtrace and which kernel locks are held.
+//!
+//! who holds a lock is only known with the `lockdebug` feature, see `lock`.
+//! without it the report only says which locks are held.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::exceptions::{self, TrapFrame};
+use crate::lock::Mutex;
+use crate::smp::MAX_CPUS;
+use crate::symbols::Symbol;
+use crate::{backtrace, interrupts, ioapic, percpu, println, time, tlb, vga_buffer};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+/// an hour, longer timeouts are cut down to it
+pub const MAX_TIMEOUT_SECS: u64 = 3600;
+
+const PIT_IRQ: u8 = 0;
+const RFLAGS_IF: u64 = 1 << 9;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECS);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+/// bumped whenever a CPU shows it's making progress
+static HEARTBEAT: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+/// set while a CPU waits in `hlt` with nothing to do
+static IDLE: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+/// the heartbeat at the last NMI, and how many NMIs it didn't move
+static LAST_HEARTBEAT: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+static STALLED_NMIS: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+/// so a lockup is only reported once
+static REPORTED: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+
+/// this CPU is making progress
+pub fn pet() {
+    HEARTBEAT[percpu::cpu_id()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// around `hlt` in the idle loop
+pub fn set_idle(idle: bool) {
+    IDLE[percpu::cpu_id()].store(idle, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn timeout_secs() -> u64 {
+    TIMEOUT_SECS.load(Ordering::Relaxed)
+}
+
+/// start watching every CPU. the APs have to be running already, an NMI
+/// would catch one still in the trampoline without an IDT
+pub fn enable(timeout_secs: u64) -> bool {
+    TIMEOUT_SECS.store(timeout_secs.clamp(1, MAX_TIMEOUT_SECS), Ordering::Relaxed);
+    for cpu in 0..MAX_CPUS {
+        STALLED_NMIS[cpu].store(0, Ordering::Relaxed);
+        REPORTED[cpu].store(false, Ordering::Relaxed);
+    }
+    if !ioapic::is_initialized() && !ioapic::init() {
+        return false;
+    }
+    // before the first NMI can arrive
+    ENABLED.store(true, Ordering::SeqCst);
+    if !ioapic::route_isa_irq_as_nmi(PIT_IRQ) {
+        ENABLED.store(false, Ordering::SeqCst);
+        return false;
+    }
+    true
+}
+
+pub fn disable() {
+    ioapic::mask_isa_irq(PIT_IRQ);
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// a lock that was held, and by whom if `lockdebug` knows
+#[derive(Clone, Copy)]
+struct HeldLock {
+    name: &'static str,
+    holder: Option<(usize, &'static Location<'static>)>,
+}
+
+fn held<T>(lock: &Mutex<T>) -> Option<HeldLock> {
+    let holder = lock.holder();
+    if holder.is_none() && !lock.is_locked() {
+        return None;
+    }
+    Some(HeldLock { name: lock.name(), holder })
+}
+
+/// the kernel locks an interrupt handler or another CPU could be stuck on,
+/// taken before `report` unjams the console ones
+fn held_locks() -> [Option<HeldLock>; 9] {
+    [
+        held(&crate::vga_buffer::WRITER),
+        held(&crate::OSINFO),
+        held(&interrupts::PICS),
+        held(&interrupts::KEYBOARD),
+        held(&tlb::LOCK),
+        held(&crate::serial::COM1),
+        held(&crate::serial::COM2),
+        held(&crate::serial::COM3),
+        held(&crate::serial::COM4),
+    ]
+}
+
+fn print_held_locks(locks: &[Option<HeldLock>]) {
+    let mut any = false;
+    for lock in locks.iter().flatten() {
+        match lock.holder {
+            Some((cpu, location)) => println!("lock held: {} by CPU {} at {}", lock.name, cpu, location),
+            None => println!("lock held: {}", lock.name),
+        }
+        any = true;
+    }
+    if !any {
+        println!("no kernel locks held");
+    }
+}
+
+fn report(frame: &TrapFrame, cpu: usize) {
+    // the console locks are the likeliest culprits, see who has them first
+    let locks = held_locks();
+    exceptions::unjam_console();
+    let _color = vga_buffer::set_foreground(vga_buffer::ERROR_COLOR);
+    println!();
+    println!("WATCHDOG: CPU {} made no progress for {}s ({}, IRQ depth {})", cpu, timeout_secs(),
+        if frame.rflags & RFLAGS_IF != 0 { "interrupts on" } else { "interrupts off" },
+        percpu::current().irq_depth());
+    println!("RIP {:#018x} {}", frame.rip, Symbol(frame.rip));
+    backtrace::print_from(frame.rip, frame.rbp);
+    print_held_locks(&locks);
+}
+
+/// called from `exception_dispatch` for every NMI, returns whether the NMI
+/// was ours. the watchdog can't tell its NMIs from others, so while it's
+/// enabled that's all of them
+pub fn handle_nmi(frame: &TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let cpu = percpu::cpu_id();
+    let heartbeat = HEARTBEAT[cpu].load(Ordering::Relaxed);
+    // an interrupt handler that woke the idle loop up isn't idle
+    let idle = IDLE[cpu].load(Ordering::Relaxed) && percpu::current().irq_depth() == 0;
+
+    if idle || LAST_HEARTBEAT[cpu].swap(heartbeat, Ordering::Relaxed) != heartbeat {
+        STALLED_NMIS[cpu].store(0, Ordering::Relaxed);
+        // no message, the console may well be locked by the code we interrupted
+        REPORTED[cpu].store(false, Ordering::Relaxed);
+        return true;
+    }
+
+    let stalled = STALLED_NMIS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled >= time::ms_to_ticks(timeout_secs().saturating_mul(1000)) && !REPORTED[cpu].swap(true, Ordering::Relaxed) {
+        report(frame, cpu);
+    }
+    true
+}
+
+/// the `watchdog` shell command without arguments
+pub fn print_status() {
+    if is_enabled() {
+        println!("watchdog on, timeout {}s", timeout_secs());
+    } else {
+        println!("watchdog off");
+    }
+    for cpu in 0..MAX_CPUS {
+        if !crate::smp::is_online(cpu) {
+            continue;
+        }
+        println!("CPU {}: heartbeat {}{}", cpu, HEARTBEAT[cpu].load(Ordering::Relaxed),
+            if REPORTED[cpu].load(Ordering::Relaxed) { " STUCK" } else { "" });
+    }
+}